@@ -0,0 +1,166 @@
+/*!
+ * Interface between the kernel and the applications.
+ *
+ * The system call table is defined once here and used both by the kernel to
+ * generate its dispatch function and by rustberry_std to generate the
+ * user-side stubs. Each entry gives the number of the system call, its name,
+ * the registers holding its arguments and the registers holding its results.
+ *
+ * The number of the system call is passed in r7. Applications also encode it
+ * in the svc immediate so that they keep working with kernels built with the
+ * `svc_immediate` compatibility feature, but the kernel ignores it otherwise.
+ */
+
+#![no_std]
+
+/**
+ * Call the macro `$callback` with the whole system call table.
+ * Entries have the form `number => name(reg: arg, ...) -> (reg, ...);`.
+ *
+ * Numbers are part of the ABI: they must never be reused or changed.
+ * The kernel dispatch function denies unreachable patterns so a duplicated
+ * number is a compilation error.
+ *
+ * The register lists are only used to generate the user-side stubs. Kernel
+ * handlers read their arguments and write their results in the register
+ * context themselves, nothing checks that they agree with the table: both
+ * must be updated together.
+ */
+#[macro_export]
+macro_rules! syscall_table {
+    ($callback:ident) => {
+        $callback! {
+            0 => reschedule() -> ();
+            1 => read(r0: fd, r1: buf, r2: len) -> (r0);
+            2 => write(r0: fd, r1: buf, r2: len) -> (r0);
+            3 => open(r0: path, r1: path_len) -> (r0);
+            4 => close(r0: fd) -> (r0);
+            5 => exit(r0: exit_code) -> ();
+            6 => kill(r0: pid, r1: signal) -> (r0);
+            7 => reserve_heap_pages(r0: nb_pages) -> (r0);
+            8 => sleep(r0: msec) -> ();
+            9 => wait_children() -> (r0, r1, r2);
+            10 => seek(r0: fd, r1: origin, r2: offset_high, r3: offset_low) -> (r0, r1, r2);
+            11 => spawn(r0: path, r1: path_len, r2: args, r3: nb_args) -> (r0);
+            12 => fork() -> (r0);
+            13 => exec(r0: path, r1: path_len, r2: args, r3: nb_args) -> (r0);
+            14 => set_close_on_exec(r0: fd, r1: close_on_exec) -> (r0);
+            15 => pipe() -> (r0, r1);
+            16 => dup(r0: fd) -> (r0);
+            17 => dup2(r0: fd, r1: target) -> (r0);
+            18 => sigaction(r0: signal, r1: handler, r2: restorer, r3: mask) -> (r0);
+            19 => sigprocmask(r0: how, r1: mask) -> (r0, r1);
+            20 => sigreturn() -> ();
+            21 => waitpid(r0: pid, r1: flags) -> (r0, r1, r2);
+            22 => nice(r0: increment) -> (r0);
+            23 => getpriority(r0: pid) -> (r0);
+            24 => setpriority(r0: pid, r1: nice) -> (r0);
+            25 => getrusage(r0: who, r1: usage) -> (r0);
+            26 => list_processes(r0: infos, r1: max_infos) -> (r0);
+            27 => mmap(r0: addr, r1: len, r2: prot) -> (r0);
+            28 => mmap_file(r0: fd, r1: offset, r2: len, r3: prot) -> (r0);
+            29 => munmap(r0: addr, r1: len) -> (r0);
+            30 => mprotect(r0: addr, r1: len, r2: prot) -> (r0);
+            31 => get_page_limit(r0: pid) -> (r0);
+            32 => set_page_limit(r0: pid, r1: nb_pages) -> (r0);
+        }
+    };
+}
+
+/**
+ * Offset added to the nice values returned by system calls, so that they are
+ * never negative and cannot be mistaken for error numbers.
+ */
+pub const NICE_OFFSET: i32 = 20;
+
+/// Value of the `who` argument of `getrusage` for the calling process
+pub const RUSAGE_SELF: u32 = 0;
+/// Value of the `who` argument of `getrusage` for the ended children
+pub const RUSAGE_CHILDREN: u32 = !0;
+
+/// Processor time used by processes, filled by `getrusage`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResourceUsage {
+    /// Time spent executing the application, in microseconds
+    pub user_time: u64,
+    /// Time spent in the kernel on behalf of the application, in microseconds
+    pub system_time: u64,
+}
+
+/// Protection flags of `mmap`, `mmap_file` and `mprotect`, pages with
+/// PROT_NONE cannot be accessed at all
+pub const PROT_NONE: u32 = 0;
+pub const PROT_READ: u32 = 1;
+pub const PROT_WRITE: u32 = 2;
+pub const PROT_EXEC: u32 = 4;
+
+/// Page limit of `get_page_limit` and `set_page_limit` for processes that
+/// can map as many pages as they want
+pub const NO_PAGE_LIMIT: u32 = 0;
+
+/// States of a process given in `ProcessInfo::state`
+pub mod process_state {
+    pub const RUNNABLE: u32 = 0;
+    pub const BLOCKED_READING: u32 = 1;
+    pub const BLOCKED_WRITING: u32 = 2;
+    pub const WAITING_TIMER: u32 = 3;
+    pub const WAITING_CHILDREN: u32 = 4;
+    pub const WAITING_MUTEX: u32 = 5;
+    /// The process has ended and its parent has not waited for it yet
+    pub const ZOMBIE: u32 = 6;
+}
+
+/// Maximum length of `ProcessInfo::name`, longer names are truncated
+pub const PROCESS_NAME_LEN: usize = 32;
+/// Value of `ProcessInfo::core` for processes that are not running
+pub const NOT_RUNNING: u32 = !0;
+
+/// Description of a process, filled by `list_processes`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ProcessInfo {
+    /// Time since boot at which the process started, in microseconds
+    pub start_time: u64,
+    /// Same as `ResourceUsage::user_time`
+    pub user_time: u64,
+    /// Same as `ResourceUsage::system_time`
+    pub system_time: u64,
+    pub pid: u32,
+    pub parent_pid: u32,
+    /// One of the constants of `process_state`
+    pub state: u32,
+    /// Core running the process or NOT_RUNNING
+    pub core: u32,
+    pub nice: i32,
+    /// Name of the process, padded with zeros
+    pub name: [u8; PROCESS_NAME_LEN],
+}
+
+impl ProcessInfo {
+    /// Return the name of the process without padding.
+    pub fn name_str(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(PROCESS_NAME_LEN);
+        // Truncation may have cut a multibyte character
+        match core::str::from_utf8(&self.name[..len]) {
+            Ok(name) => name,
+            Err(err) => core::str::from_utf8(&self.name[..err.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+macro_rules! syscall_numbers {
+    ($($num:tt => $name:ident($($reg:ident: $arg:ident),*) -> ($($ret:ident),*);)*) => {
+        $(pub const $name: u32 = $num;)*
+    };
+}
+
+/// Number of each system call, named after the entries of `syscall_table`.
+#[allow(non_upper_case_globals)]
+pub mod number {
+    syscall_table!(syscall_numbers);
+}
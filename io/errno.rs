@@ -0,0 +1,203 @@
+//! Stable error numbers used between the kernel and applications.
+//!
+//! A failing system call returns the negated error number in r0. Values
+//! between `-MAX_ERRNO` and `-1` are never valid results so they can be told
+//! apart from addresses, sizes or descriptors.
+//!
+//! These numbers are part of the system call ABI: existing values must never
+//! be changed, new ones must be appended.
+
+use crate::{Error, ErrorKind};
+
+/// Highest error number, all numbers are between 1 and this value.
+pub const MAX_ERRNO: u32 = 4095;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Errno {
+    // One error number for each `ErrorKind`
+    NotFound = 1,
+    PermissionDenied = 2,
+    ConnectionRefused = 3,
+    ConnectionReset = 4,
+    ConnectionAborted = 5,
+    NotConnected = 6,
+    AddrInUse = 7,
+    AddrNotAvailable = 8,
+    BrokenPipe = 9,
+    AlreadyExists = 10,
+    WouldBlock = 11,
+    InvalidInput = 12,
+    InvalidData = 13,
+    TimedOut = 14,
+    WriteZero = 15,
+    Interrupted = 16,
+    Other = 17,
+    UnexpectedEof = 18,
+
+    // Errors specific to system calls
+    /// The file descriptor is not open
+    BadFileDescriptor = 32,
+    /// A pointer given to the kernel is outside of application memory
+    BadAddress = 33,
+    /// The process does not exist
+    NoSuchProcess = 34,
+    /// There is no child to wait for
+    NoChild = 35,
+    /// The memory of the application cannot grow anymore
+    OutOfMemory = 36,
+    /// The file is not a valid executable
+    ExecFormat = 37,
+}
+
+impl Errno {
+    /// Decode an error number, returns None if it is unknown.
+    pub fn from_raw(errno: u32) -> Option<Errno> {
+        use self::Errno::*;
+        Some(match errno {
+            1 => NotFound,
+            2 => PermissionDenied,
+            3 => ConnectionRefused,
+            4 => ConnectionReset,
+            5 => ConnectionAborted,
+            6 => NotConnected,
+            7 => AddrInUse,
+            8 => AddrNotAvailable,
+            9 => BrokenPipe,
+            10 => AlreadyExists,
+            11 => WouldBlock,
+            12 => InvalidInput,
+            13 => InvalidData,
+            14 => TimedOut,
+            15 => WriteZero,
+            16 => Interrupted,
+            17 => Other,
+            18 => UnexpectedEof,
+            32 => BadFileDescriptor,
+            33 => BadAddress,
+            34 => NoSuchProcess,
+            35 => NoChild,
+            36 => OutOfMemory,
+            37 => ExecFormat,
+            _ => return None,
+        })
+    }
+
+    /// Value returned in r0 by a system call failing with this error.
+    pub fn to_return_value(self) -> u32 {
+        (self as u32).wrapping_neg()
+    }
+
+    /**
+     * Decode the value returned in r0 by a system call.
+     * Returns the value itself if it is not an error.
+     */
+    pub fn decode_return_value(value: u32) -> Result<u32, Errno> {
+        let errno = value.wrapping_neg();
+        if errno == 0 || errno > MAX_ERRNO {
+            Ok(value)
+        } else {
+            Err(Errno::from_raw(errno).unwrap_or(Errno::Other))
+        }
+    }
+
+    pub fn kind(self) -> ErrorKind {
+        use self::Errno::*;
+        match self {
+            NotFound => ErrorKind::NotFound,
+            PermissionDenied => ErrorKind::PermissionDenied,
+            ConnectionRefused => ErrorKind::ConnectionRefused,
+            ConnectionReset => ErrorKind::ConnectionReset,
+            ConnectionAborted => ErrorKind::ConnectionAborted,
+            NotConnected => ErrorKind::NotConnected,
+            AddrInUse => ErrorKind::AddrInUse,
+            AddrNotAvailable => ErrorKind::AddrNotAvailable,
+            BrokenPipe => ErrorKind::BrokenPipe,
+            AlreadyExists => ErrorKind::AlreadyExists,
+            WouldBlock => ErrorKind::WouldBlock,
+            InvalidInput => ErrorKind::InvalidInput,
+            InvalidData => ErrorKind::InvalidData,
+            TimedOut => ErrorKind::TimedOut,
+            WriteZero => ErrorKind::WriteZero,
+            Interrupted => ErrorKind::Interrupted,
+            Other => ErrorKind::Other,
+            UnexpectedEof => ErrorKind::UnexpectedEof,
+            BadFileDescriptor => ErrorKind::NotFound,
+            BadAddress => ErrorKind::InvalidInput,
+            NoSuchProcess => ErrorKind::NotFound,
+            NoChild => ErrorKind::NotFound,
+            OutOfMemory => ErrorKind::Other,
+            ExecFormat => ErrorKind::InvalidData,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        use self::Errno::*;
+        match self {
+            NotFound => "entity not found",
+            PermissionDenied => "permission denied",
+            ConnectionRefused => "connection refused",
+            ConnectionReset => "connection reset",
+            ConnectionAborted => "connection aborted",
+            NotConnected => "not connected",
+            AddrInUse => "address in use",
+            AddrNotAvailable => "address not available",
+            BrokenPipe => "broken pipe",
+            AlreadyExists => "entity already exists",
+            WouldBlock => "operation would block",
+            InvalidInput => "invalid input parameter",
+            InvalidData => "invalid data",
+            TimedOut => "timed out",
+            WriteZero => "write zero",
+            Interrupted => "operation interrupted",
+            Other => "other os error",
+            UnexpectedEof => "unexpected end of file",
+            BadFileDescriptor => "bad file descriptor",
+            BadAddress => "bad address",
+            NoSuchProcess => "no such process",
+            NoChild => "no child process",
+            OutOfMemory => "out of memory",
+            ExecFormat => "exec format error",
+        }
+    }
+}
+
+impl From<ErrorKind> for Errno {
+    fn from(kind: ErrorKind) -> Errno {
+        match kind {
+            ErrorKind::NotFound => Errno::NotFound,
+            ErrorKind::PermissionDenied => Errno::PermissionDenied,
+            ErrorKind::ConnectionRefused => Errno::ConnectionRefused,
+            ErrorKind::ConnectionReset => Errno::ConnectionReset,
+            ErrorKind::ConnectionAborted => Errno::ConnectionAborted,
+            ErrorKind::NotConnected => Errno::NotConnected,
+            ErrorKind::AddrInUse => Errno::AddrInUse,
+            ErrorKind::AddrNotAvailable => Errno::AddrNotAvailable,
+            ErrorKind::BrokenPipe => Errno::BrokenPipe,
+            ErrorKind::AlreadyExists => Errno::AlreadyExists,
+            ErrorKind::WouldBlock => Errno::WouldBlock,
+            ErrorKind::InvalidInput => Errno::InvalidInput,
+            ErrorKind::InvalidData => Errno::InvalidData,
+            ErrorKind::TimedOut => Errno::TimedOut,
+            ErrorKind::WriteZero => Errno::WriteZero,
+            ErrorKind::Interrupted => Errno::Interrupted,
+            ErrorKind::Other => Errno::Other,
+            ErrorKind::UnexpectedEof => Errno::UnexpectedEof,
+        }
+    }
+}
+
+impl From<Error> for Errno {
+    fn from(err: Error) -> Errno {
+        Errno::from(err.kind)
+    }
+}
+
+impl From<Errno> for Error {
+    fn from(errno: Errno) -> Error {
+        Error {
+            kind: errno.kind(),
+            error: errno.description(),
+        }
+    }
+}
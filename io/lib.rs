@@ -277,7 +277,9 @@
 
 use core::fmt;
 
+mod errno;
 mod error;
+pub use errno::*;
 pub use error::*;
 
 /// The `Read` trait allows for reading bytes from a source.
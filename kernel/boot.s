@@ -4,11 +4,16 @@
 .globl reset
 // Entry point for the kernel as a reset can be thought of as a restart.
 reset:
-    // Halt all cores except one.
+    // Make the caches of this core coherent with other cores (ACTLR.SMP)
+    mrc p15, #0, r4, c1, c0, #1
+    orr r4, r4, #0x40
+    mcr p15, #0, r4, c1, c0, #1
+
+    // Only core 0 boots the kernel, others wait until it starts them.
     mrc p15, #0, r4, c0, c0, #5
     and r4, r4, #3
     cmp r4, #0
-    bne idle
+    bne secondary_wait
 
     // Clear out bss.
     ldr r4, =__bss_start
@@ -32,9 +37,11 @@ reset:
     mov sp, #0x8000
     bl init_memory_map
 
-    // Set abort mode stack
+    // Set abort mode stack, abort handlers find it in TPIDRPRW
+    mov r4, #0x2000
+    mcr p15, #0, r4, c13, c0, #4
     cps #0x17
-    mov sp, #0x2000
+    mov sp, r4
 
     // Set supervisor mode stack
     cps #0x13
@@ -48,3 +55,53 @@ reset:
 idle:
     wfi
     b idle
+
+// Secondary core boot code does not need to be in the first kernel page
+.section .text.secondary_boot
+
+// Wait until core 0 writes an entry point in mailbox 3 of this core, like the
+// firmware does for secondary cores when it has not been overwritten.
+secondary_wait:
+    ldr r5, =0x400000CC
+    add r5, r5, r4, lsl #4
+1:
+    wfe
+    ldr r6, [r5]
+    cmp r6, #0
+    beq 1b
+    str r6, [r5]    // Clear the mailbox
+    bx r6
+
+.globl secondary_reset
+// Entry point of cores 1 to 3 when core 0 starts them.
+// Core 0 writes the top of the abort stack of the core in its mailbox 2.
+secondary_reset:
+    // Also set there in case the firmware started this core
+    mrc p15, #0, r4, c1, c0, #1
+    orr r4, r4, #0x40
+    mcr p15, #0, r4, c1, c0, #1
+
+    mrc p15, #0, r4, c0, c0, #5
+    and r4, r4, #3
+    ldr r5, =0x400000C8
+    add r5, r5, r4, lsl #4
+    ldr r6, [r5]
+    str r6, [r5]    // Clear the mailbox
+
+    // The abort stack is identity mapped, use it until the MMU is enabled
+    cps #0x13
+    mov sp, r6
+    bl secondary_init_memory_map
+
+    // Set abort mode stack, abort handlers find it in TPIDRPRW
+    mcr p15, #0, r6, c13, c0, #4
+    cps #0x17
+    mov sp, r6
+
+    // Supervisor stack of core n starts at 0x8000_0000 - n * 0x0400_0000
+    cps #0x13
+    mov sp, #0x80000000
+    sub sp, sp, r4, lsl #26
+
+    bl secondary_main
+    b idle
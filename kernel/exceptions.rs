@@ -1,6 +1,11 @@
-use crate::memory::{application_map, kernel_map};
-use crate::process::RegisterContext;
+use crate::memory::application_map::{self, AppMapError};
+use crate::memory::kernel_map;
+#[cfg(feature = "svc_immediate")]
+use crate::memory::user_slice::copy_from_user;
+use crate::process::{ExitStatus, RegisterContext};
 use crate::scheduler;
+use crate::signal;
+use crate::smp;
 use crate::syscall;
 use crate::system_control;
 use crate::system_control::ProcessorMode;
@@ -8,6 +13,8 @@ use drivers;
 
 #[no_mangle]
 pub extern "C" fn undefined_instruction_handler(reg_ctx: &mut RegisterContext) {
+    smp::lock_kernel();
+    scheduler::account_kernel_entry(reg_ctx);
     let instr_addr = reg_ctx.pc;
     if system_control::get_spsr() & 0b11111 == ProcessorMode::User as u32 {
         // Error in application code
@@ -22,35 +29,70 @@ pub extern "C" fn undefined_instruction_handler(reg_ctx: &mut RegisterContext) {
                 instr_addr
             );
         }
-        syscall::exit(103);
+        // Faults cannot be caught, they always terminate the process
+        if let Some(pid) = scheduler::current_pid() {
+            scheduler::terminate_process(pid, ExitStatus::Signaled(signal::SIGILL));
+        }
         scheduler::check_schedule(reg_ctx);
+        smp::unlock_kernel();
     } else {
         // Error in kernel code
         panic!("Undefined kernel instruction at {:p}", instr_addr);
     }
 }
 
+macro_rules! syscall_dispatch {
+    ($($num:tt => $name:ident($($reg:ident: $arg:ident),*) -> ($($ret:ident),*);)*) => {
+        // Only numbers and names are used, each handler decodes its own registers.
+        // Two entries with the same number would make one of the arms unreachable
+        #[deny(unreachable_patterns)]
+        fn dispatch_syscall(syscall_id: u32, reg_ctx: &mut RegisterContext) {
+            match syscall_id {
+                $($num => syscall::$name(reg_ctx),)*
+                _ => warn!("Invalid syscall {}", syscall_id),
+            }
+        }
+    };
+}
+
+syscall_table!(syscall_dispatch);
+
+// The system call number is passed in r7, the svc immediate is ignored
+#[cfg(not(feature = "svc_immediate"))]
+fn syscall_number(reg_ctx: &RegisterContext) -> u32 {
+    reg_ctx.r7
+}
+
+/**
+ * Compatibility with programs passing the system call number in the svc
+ * immediate. Thumb code and the kernel itself always use r7.
+ * Returns None if the svc instruction cannot be read.
+ */
+#[cfg(feature = "svc_immediate")]
+fn syscall_number(reg_ctx: &RegisterContext) -> Option<u32> {
+    let from_user = reg_ctx.psr & 0b11111 == ProcessorMode::User as u32;
+    if reg_ctx.is_thumb() || !from_user {
+        return Some(reg_ctx.r7);
+    }
+    let svc_addr = (reg_ctx.pc as usize).wrapping_sub(4);
+    let instruction: u32 = copy_from_user(svc_addr).ok()?;
+    Some(instruction & 0x00ff_ffff)
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn software_interrupt_handler(reg_ctx: &mut RegisterContext) {
-    let syscall_id = *(reg_ctx.pc.offset(-1)) & 0x00ff_ffff;
-
-    match syscall_id {
-        0 => scheduler::plan_scheduling(),
-        1 => syscall::read(reg_ctx),
-        2 => syscall::write(reg_ctx),
-        3 => syscall::open(reg_ctx),
-        4 => syscall::close(reg_ctx),
-        5 => syscall::exit(reg_ctx.r0),
-        6 => syscall::kill(reg_ctx),
-        7 => syscall::reserve_heap_pages(reg_ctx),
-        8 => syscall::sleep(reg_ctx),
-        9 => syscall::wait_children(reg_ctx),
-        10 => syscall::seek(reg_ctx),
-        /*11 => syscall::spawn(reg_ctx),*/
-        _ => warn!("Invalid syscall {}", syscall_id),
+    smp::lock_kernel();
+    scheduler::account_kernel_entry(reg_ctx);
+    #[cfg(not(feature = "svc_immediate"))]
+    dispatch_syscall(syscall_number(reg_ctx), reg_ctx);
+    #[cfg(feature = "svc_immediate")]
+    match syscall_number(reg_ctx) {
+        Some(syscall_id) => dispatch_syscall(syscall_id, reg_ctx),
+        None => warn!("Cannot read svc instruction at {:p}", reg_ctx.pc),
     }
 
     scheduler::check_schedule(reg_ctx);
+    smp::unlock_kernel();
 }
 
 fn fault_description(status: u32) -> &'static str {
@@ -88,11 +130,67 @@ fn fault_description(status: u32) -> &'static str {
     }
 }
 
+// Terminate the current process from an abort handler and switch to another.
+// The abort handler never returns so it must release the kernel lock here.
+fn terminate_on_fault(signal: u32) {
+    if let Some(pid) = scheduler::current_pid() {
+        scheduler::terminate_process(pid, ExitStatus::Signaled(signal));
+    }
+    smp::unlock_kernel();
+    unsafe {
+        asm!("svc 0" :: "{r7}"(abi::number::reschedule) :: "volatile");
+    }
+}
+
+/**
+ * Check the result of mapping a page on a fault of the application, return
+ * true if the faulting instruction can be retried.
+ * When physical memory is exhausted, the process mapping the most pages is
+ * killed to free memory. If it is the current process, it is terminated and
+ * this function does not return.
+ */
+fn user_page_fault_resolved(result: Result<(), AppMapError>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(AppMapError::OutOfMemory) => {
+            let current_pid = scheduler::current_pid();
+            match scheduler::out_of_memory_victim() {
+                Some(pid) if Some(pid) != current_pid => {
+                    let victim = scheduler::get_process(pid).unwrap();
+                    error!("Out of memory, killing {} (pid {})", victim.name, pid);
+                    // Its memory may only be freed once another core stops it
+                    let _ = signal::send(pid, signal::SIGKILL);
+                    true
+                }
+                _ => {
+                    if let Some(process) = scheduler::current_process() {
+                        error!("{}: Out of memory, killing it", process.name);
+                    }
+                    terminate_on_fault(signal::SIGKILL);
+                    false
+                }
+            }
+        }
+        Err(_) => false,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn prefetch_abort_handler(instr_addr: usize, status: u32) {
+    smp::lock_kernel();
+    let translation_fault = status & (0b1101 | 1 << 10) == 0b0101;
     let fault_desc = fault_description(status);
 
     if system_control::get_spsr() & 0b11111 == ProcessorMode::User as u32 {
+        // Program pages are mapped on their first access
+        if translation_fault
+            && instr_addr >= application_map::FIRST_PRGM_PAGE.to_addr()
+            && user_page_fault_resolved(application_map::load_current_page(instr_addr))
+        {
+            smp::unlock_kernel();
+            return;
+        }
+
         if let Some(process) = scheduler::current_process() {
             error!(
                 "{}: Prefetch abort at instruction {:#x}: {}.",
@@ -101,7 +199,7 @@ pub extern "C" fn prefetch_abort_handler(instr_addr: usize, status: u32) {
         } else {
             panic!("Prefetch abort while no current process running");
         }
-        unsafe { asm!("svc 5" :: "{r0}"(104) :: "volatile") } // Syscall exit
+        terminate_on_fault(signal::SIGSEGV);
     } else {
         panic!(
             "Prefetch abort at instruction {:#x}: {}.",
@@ -112,9 +210,23 @@ pub extern "C" fn prefetch_abort_handler(instr_addr: usize, status: u32) {
 
 #[no_mangle]
 pub extern "C" fn data_abort_handler(instr_addr: usize, data_addr: usize, status: u32) {
+    // Aborts in kernel code happen with the lock already held by this core
+    smp::lock_kernel();
     let translation_fault = status & (0b1101 | 1 << 10) == 0b0101;
+    let permission_fault = status & (0b1101 | 1 << 10) == 0b1101;
     let cache = status & (1 << 13) != 0;
     let write = status & (1 << 11) != 0;
+    let user_mode = system_control::get_spsr() & 0b11111 == ProcessorMode::User as u32;
+    // Faults of kernel code are not recovered when memory is exhausted, it
+    // must check application memory before accessing it (see
+    // `check_user_range`)
+    let resolved = |result: Result<(), AppMapError>| {
+        if user_mode {
+            user_page_fault_resolved(result)
+        } else {
+            result.is_ok()
+        }
+    };
 
     if translation_fault && write {
         // If we get a fault on a stack, try to make it grow and
@@ -123,16 +235,35 @@ pub extern "C" fn data_abort_handler(instr_addr: usize, data_addr: usize, status
             && data_addr < kernel_map::FIRST_APPLICATION_PAGE.to_addr()
         {
             kernel_map::grow_svc_stack(data_addr);
+            smp::unlock_kernel();
+            return;
+        } else if data_addr >= application_map::STACK_PAGE_LIMIT.to_addr()
+            && resolved(application_map::grow_current_stack(data_addr))
+        {
+            smp::unlock_kernel();
             return;
-        } else if data_addr >= application_map::STACK_PAGE_LIMIT.to_addr() {
-            application_map::grow_current_stack(data_addr).unwrap();
+        }
+    }
+
+    if translation_fault && data_addr >= application_map::FIRST_PRGM_PAGE.to_addr() {
+        // Program and heap pages are mapped on their first access
+        if resolved(application_map::load_current_page(data_addr)) {
+            smp::unlock_kernel();
+            return;
+        }
+    }
+
+    if permission_fault && write && data_addr >= application_map::FIRST_PRGM_PAGE.to_addr() {
+        // Writes to pages shared after a fork are allowed after a copy
+        if resolved(application_map::copy_current_page_on_write(data_addr)) {
+            smp::unlock_kernel();
             return;
         }
     }
 
     let fault_desc = fault_description(status);
 
-    if system_control::get_spsr() & 0b11111 == ProcessorMode::User as u32 {
+    if user_mode {
         // Do not panic on wrong application code
         if let Some(process) = scheduler::current_process() {
             error!(
@@ -150,7 +281,7 @@ pub extern "C" fn data_abort_handler(instr_addr: usize, data_addr: usize, status
                 fault_desc
             );
         }
-        unsafe { asm!("svc 5" :: "{r0}"(105) :: "volatile") } // Syscall exit
+        terminate_on_fault(signal::SIGSEGV);
     } else {
         panic!(
             "Data abort at instruction {:#x}.\n\
@@ -166,10 +297,15 @@ pub extern "C" fn data_abort_handler(instr_addr: usize, data_addr: usize, status
 
 #[no_mangle]
 pub extern "C" fn irq_handler(reg_ctx: &mut RegisterContext) {
+    smp::lock_kernel();
+    scheduler::account_kernel_entry(reg_ctx);
     drivers::interrupts::handle_irq();
     scheduler::check_schedule(reg_ctx);
+    smp::unlock_kernel();
 }
 
+// FIQs are not masked in kernel code and may interrupt the acquisition of the
+// kernel lock, so their handlers must not access kernel data structures.
 #[no_mangle]
 pub extern "C" fn fiq_handler() {
     drivers::interrupts::handle_fiq();
@@ -42,7 +42,7 @@ software_interrupt:
 
 // Prefetch abort
 prefetch_abort:
-    mov     sp, #0x2000
+    mrc     p15, #0, sp, c13, c0, #4 // Abort stack of this core
     push    {r0-r3, r12, lr}
     sub     r0, lr, #4
     mrc     p15, 0, r1, c5, c0, 1
@@ -52,7 +52,7 @@ prefetch_abort:
 
 // Data abort
 data_abort:
-    mov     sp, #0x2000
+    mrc     p15, #0, sp, c13, c0, #4 // Abort stack of this core
     push    {r0-r3, r12, lr}
     sub     r0, lr, #8
     mrc     p15, #0, r1, c6, c0, #0
@@ -3,6 +3,7 @@ use crate::filesystem::fat32::file::File as FatFile;
 use crate::filesystem::Dir as DirTrait;
 use crate::filesystem::DirEntry;
 use crate::filesystem::File;
+use crate::filesystem::FileIdentity;
 use alloc::{boxed::Box, vec::Vec};
 use io;
 
@@ -54,6 +55,27 @@ impl DirTrait for Dir {
         }*/
         panic!("")
     }
+    fn get_file_identity(&mut self, name: &str) -> io::Result<FileIdentity> {
+        let mut pos = 0;
+        loop {
+            match FatDirEntry::dump(&mut self.file, pos) {
+                Typ::Some(dir_entry) => {
+                    if !dir_entry.is_dir() && dir_entry.get_name() == name {
+                        return Ok(dir_entry.identity(self.file.filesystem_id()));
+                    }
+                    pos = dir_entry.pos + 32;
+                }
+                Typ::Unused => pos += 32,
+                Typ::None => {
+                    return Err(io::Error {
+                        kind: io::ErrorKind::NotFound,
+                        error: "File not found",
+                    })
+                }
+            }
+        }
+    }
+
     fn get_subdir(&mut self, _name: &str) -> io::Result<Box<DirTrait>> {
         unimplemented!()
     }
@@ -1,6 +1,7 @@
 use crate::filesystem::buffer_io::*;
 use crate::filesystem::fat32::file::File;
 use crate::filesystem::DirEntry as VfsDirEntry;
+use crate::filesystem::FileIdentity;
 use crate::filesystem::FileType;
 use io::*;
 
@@ -143,7 +144,7 @@ impl DirEntry {
     }
 
     pub fn fst_cluster(&self) -> u32 {
-        ((self.fst_cluster_hi as u32) << 16) & self.fst_cluster_lo as u32
+        ((self.fst_cluster_hi as u32) << 16) | self.fst_cluster_lo as u32
     }
 
     pub fn set_fst_cluster(&mut self, cluster: u32) {
@@ -151,6 +152,16 @@ impl DirEntry {
         self.fst_cluster_lo = cluster as u16;
     }
 
+    /// Identify the file of the entry in the filesystem `filesystem`.
+    pub fn identity(&self, filesystem: usize) -> FileIdentity {
+        FileIdentity {
+            filesystem,
+            node: self.fst_cluster() as u64,
+            size: self.size as usize,
+            modified: (self.modify_date as u32) << 16 | self.modify_time as u32,
+        }
+    }
+
     pub fn to_vfs_dir_entry(&self) -> VfsDirEntry {
         let name = if let Some(ref name) = self.long_name {
             name.clone()
@@ -43,6 +43,11 @@ impl File {
         }
     }
 
+    /// Identifier of the filesystem of the file, shared by all its files
+    pub fn filesystem_id(&self) -> usize {
+        &*self.fs as *const Fat as usize
+    }
+
     pub fn update_size(&mut self) {
         ()
     }
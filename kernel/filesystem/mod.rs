@@ -31,6 +31,22 @@ impl DirEntry {
     }
 }
 
+/**
+ * Identity of the content of a file, built by its filesystem from the
+ * metadata of the file. Two files with the same identity have the same
+ * content, so it is used to cache what is read from them.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct FileIdentity {
+    /// Distinguishes the mounted filesystems
+    pub filesystem: usize,
+    /// Identifies the file inside its filesystem (inode, first cluster...)
+    pub node: u64,
+    pub size: usize,
+    /// Last modification time, in the format of the filesystem
+    pub modified: u32,
+}
+
 pub trait Dir: 'static {
     fn list_entries(&mut self) -> Vec<DirEntry>;
 
@@ -42,6 +58,14 @@ pub trait Dir: 'static {
 
     fn box_clone(&self) -> Box<Dir>;
 
+    /// Identity of a file, filesystems that cannot provide it return an error.
+    fn get_file_identity(&mut self, _name: &str) -> io::Result<FileIdentity> {
+        Err(io::Error {
+            kind: io::ErrorKind::Other,
+            error: "file identity not supported by the filesystem",
+        })
+    }
+
     fn open_file(&mut self, path: &str) -> io::Result<Box<File>> {
         let path: Vec<&str> = path.rsplitn(2, '/').collect();
         if path.len() == 1 {
@@ -52,6 +76,16 @@ pub trait Dir: 'static {
         }
     }
 
+    fn file_identity(&mut self, path: &str) -> io::Result<FileIdentity> {
+        let path: Vec<&str> = path.rsplitn(2, '/').collect();
+        if path.len() == 1 {
+            self.get_file_identity(path[0])
+        } else {
+            let mut dir = self.open_dir(path[1])?;
+            dir.get_file_identity(path[0])
+        }
+    }
+
     fn open_dir(&mut self, path: &str) -> io::Result<Box<Dir>> {
         let mut current_dir: Option<Box<Dir>> = None;
         for subdir in path.split('/') {
@@ -116,4 +150,5 @@ pub mod buffer_io;
 pub mod devfs;
 pub mod fat32;
 pub mod mbr_reader;
+pub mod pipe;
 pub mod virtualfs;
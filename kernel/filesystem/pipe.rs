@@ -0,0 +1,229 @@
+/*!
+ * Anonymous pipes between processes.
+ *
+ * A pipe is a kernel ring buffer shared by a read end and a write end.
+ * When an operation cannot progress, a `WouldBlock` error is returned and the
+ * pipe is recorded in the `pipe_waiter` of the calling process. If the system
+ * call layer suspends the process, it registers it as waiting on the pipe
+ * (see `PipeWaiter::register`) and restarts the call once it is woken up.
+ */
+
+use crate::process::ProcessState;
+use crate::scheduler;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::rc::{Rc, Weak};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::cmp::min;
+use io;
+use io::{Read, Seek, Write};
+
+const PIPE_CAPACITY: usize = 4096;
+
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    nb_readers: usize,
+    nb_writers: usize,
+    waiting_readers: Vec<usize>,
+    waiting_writers: Vec<usize>,
+}
+
+pub struct PipeReader {
+    pipe: Rc<RefCell<PipeBuffer>>,
+}
+
+pub struct PipeWriter {
+    pipe: Rc<RefCell<PipeBuffer>>,
+}
+
+/**
+ * Pipe on which a process cannot progress, kept by the process to join the
+ * waiting lists when it blocks, and to leave them if it is woken up by
+ * something else or removed.
+ */
+pub struct PipeWaiter {
+    pipe: Weak<RefCell<PipeBuffer>>,
+    writing: bool,
+}
+
+impl PipeWaiter {
+    /// Add the process `pid`, which is about to block, to the waiting list.
+    pub fn register(&self, pid: usize) {
+        if let Some(pipe) = self.pipe.upgrade() {
+            let mut pipe = pipe.borrow_mut();
+            let waiting = if self.writing {
+                &mut pipe.waiting_writers
+            } else {
+                &mut pipe.waiting_readers
+            };
+            if !waiting.contains(&pid) {
+                waiting.push(pid);
+            }
+        }
+    }
+
+    /// Remove the process `pid` from the waiting lists of the pipe.
+    pub fn cancel(self, pid: usize) {
+        if let Some(pipe) = self.pipe.upgrade() {
+            let mut pipe = pipe.borrow_mut();
+            pipe.waiting_readers.retain(|&waiter| waiter != pid);
+            pipe.waiting_writers.retain(|&waiter| waiter != pid);
+        }
+    }
+}
+
+/// Create a new pipe and return its read and write ends.
+pub fn new() -> (PipeReader, PipeWriter) {
+    let pipe = Rc::new(RefCell::new(PipeBuffer {
+        data: VecDeque::with_capacity(PIPE_CAPACITY),
+        nb_readers: 1,
+        nb_writers: 1,
+        waiting_readers: Vec::new(),
+        waiting_writers: Vec::new(),
+    }));
+
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+// Record the pipe on which the current process cannot progress
+fn set_waiter(pipe: &Rc<RefCell<PipeBuffer>>, writing: bool) {
+    if let Some(process) = scheduler::current_process() {
+        process.pipe_waiter = Some(PipeWaiter {
+            pipe: Rc::downgrade(pipe),
+            writing,
+        });
+    }
+}
+
+/// Make runnable all the processes still blocked on the pipe.
+fn wake_up(waiting: &mut Vec<usize>, blocked_state: ProcessState) {
+    for pid in waiting.drain(..) {
+        if let Some(process) = scheduler::get_process(pid) {
+            if process.state == blocked_state {
+                process.pipe_waiter = None;
+                process.state = ProcessState::Runnable;
+                scheduler::resume_process(pid);
+            }
+        }
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.pipe.borrow_mut();
+
+        if pipe.data.is_empty() {
+            // End of file once every writer has closed the pipe
+            if pipe.nb_writers == 0 || buf.len() == 0 {
+                return Ok(0);
+            }
+
+            set_waiter(&self.pipe, false);
+            return Err(io::Error {
+                kind: io::ErrorKind::WouldBlock,
+                error: "pipe is empty",
+            });
+        }
+
+        let read_size = min(buf.len(), pipe.data.len());
+        for (byte, data) in buf.iter_mut().zip(pipe.data.drain(..read_size)) {
+            *byte = data;
+        }
+
+        wake_up(&mut pipe.waiting_writers, ProcessState::BlockedWriting);
+        Ok(read_size)
+    }
+}
+
+impl Write for PipeReader {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error {
+            kind: io::ErrorKind::PermissionDenied,
+            error: "cannot write to the read end of a pipe",
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PipeReader {
+    fn seek(&mut self, _: io::SeekFrom) -> io::Result<u64> {
+        Err(io::Error {
+            kind: io::ErrorKind::InvalidInput,
+            error: "cannot seek inside pipe",
+        })
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut pipe = self.pipe.borrow_mut();
+        pipe.nb_readers -= 1;
+        if pipe.nb_readers == 0 {
+            // Blocked writers must now fail with a broken pipe
+            wake_up(&mut pipe.waiting_writers, ProcessState::BlockedWriting);
+        }
+    }
+}
+
+impl Read for PipeWriter {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error {
+            kind: io::ErrorKind::PermissionDenied,
+            error: "cannot read from the write end of a pipe",
+        })
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.pipe.borrow_mut();
+
+        if pipe.nb_readers == 0 {
+            return Err(io::Error {
+                kind: io::ErrorKind::BrokenPipe,
+                error: "pipe has no reader",
+            });
+        }
+
+        let write_size = min(buf.len(), PIPE_CAPACITY - pipe.data.len());
+        if write_size == 0 && buf.len() != 0 {
+            set_waiter(&self.pipe, true);
+            return Err(io::Error {
+                kind: io::ErrorKind::WouldBlock,
+                error: "pipe is full",
+            });
+        }
+
+        pipe.data.extend(&buf[0..write_size]);
+
+        wake_up(&mut pipe.waiting_readers, ProcessState::BlockedReading);
+        Ok(write_size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PipeWriter {
+    fn seek(&mut self, _: io::SeekFrom) -> io::Result<u64> {
+        Err(io::Error {
+            kind: io::ErrorKind::InvalidInput,
+            error: "cannot seek inside pipe",
+        })
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut pipe = self.pipe.borrow_mut();
+        pipe.nb_writers -= 1;
+        if pipe.nb_writers == 0 {
+            // Blocked readers must now see the end of file
+            wake_up(&mut pipe.waiting_readers, ProcessState::BlockedReading);
+        }
+    }
+}
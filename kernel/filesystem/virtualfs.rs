@@ -1,4 +1,5 @@
-use crate::filesystem::{Dir, DirEntry, File, FileType};
+use crate::filesystem::{Dir, DirEntry, File, FileIdentity, FileType};
+use crate::sync::{Mutex, Once};
 use alloc::boxed::Box;
 use alloc::rc::Rc;
 use alloc::string::{String, ToString};
@@ -101,6 +102,16 @@ impl Dir for VirtualDir {
         }
     }
 
+    fn get_file_identity(&mut self, name: &str) -> io::Result<FileIdentity> {
+        match self.filesystem {
+            None => Err(io::Error {
+                kind: io::ErrorKind::NotFound,
+                error: "get_file_identity: filesystem not found",
+            }),
+            Some(ref mut fs) => fs.get_file_identity(name),
+        }
+    }
+
     fn get_subdir(&mut self, name: &str) -> io::Result<Box<Dir>> {
         match self.children.get(name) {
             Some(vsubdir) => Ok(Box::new(vsubdir.borrow().clone())),
@@ -149,12 +160,18 @@ impl Dir for VirtualDir {
     }
 }
 
-static mut VIRTUAL_ROOT: Option<VirtualDir> = None;
+// Mounted filesystems hold Rc to their devices, which are only used with the
+// kernel lock held (see `smp`)
+unsafe impl Send for VirtualDir {}
+
+static VIRTUAL_ROOT: Once<Mutex<VirtualDir>> = Once::new();
 
 pub fn init() {
-    unsafe { VIRTUAL_ROOT = Some(VirtualDir::new()) }
+    VIRTUAL_ROOT.call_once(|| Mutex::new(VirtualDir::new()));
 }
 
-pub fn get_root() -> &'static mut VirtualDir {
-    unsafe { VIRTUAL_ROOT.as_mut().unwrap() }
+pub fn get_root() -> &'static Mutex<VirtualDir> {
+    VIRTUAL_ROOT
+        .get()
+        .expect("Virtual filesystem not initialized")
 }
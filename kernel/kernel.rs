@@ -5,6 +5,8 @@
 #[macro_use]
 extern crate alloc;
 extern crate rlibc;
+#[macro_use]
+extern crate rustberry_abi as abi;
 
 #[macro_use]
 extern crate bitflags;
@@ -27,7 +29,10 @@ pub mod memory;
 pub mod panic;
 mod process;
 mod scheduler;
+mod signal;
+mod smp;
 mod sparse_vec;
+mod sync;
 pub mod syscall;
 mod system_control;
 mod timer;
@@ -37,6 +42,8 @@ use drivers::*;
 use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
 use alloc::rc::Rc;
+use alloc::sync::Arc;
+use memory::image_cache::ProgramImage;
 
 use memory::kernel_alloc::GlobalKernelAllocator;
 
@@ -53,6 +60,20 @@ pub extern "C" fn init_memory_map() {
     memory::kernel_map::init();
 }
 
+#[no_mangle]
+pub extern "C" fn secondary_init_memory_map() {
+    // Secondary cores call this function with their identity mapped abort
+    // stack and MMU disabled
+    memory::kernel_map::enable();
+}
+
+#[no_mangle]
+pub extern "C" fn secondary_main() -> ! {
+    smp::lock_kernel();
+    info!("Core {} started", smp::core_id());
+    scheduler::start_core();
+}
+
 #[no_mangle]
 pub extern "C" fn kernel_main() -> () {
     uart::init();
@@ -72,7 +93,10 @@ pub extern "C" fn kernel_main() -> () {
 
     let mut devfs = filesystem::devfs::DeviceDir::new();
     devfs.add_device("uart".to_owned(), Rc::new(uart::Uart));
-    filesystem::virtualfs::get_root().mount(Box::new(devfs), "dev");
+    filesystem::virtualfs::get_root()
+        .try_lock()
+        .unwrap()
+        .mount(Box::new(devfs), "dev");
 
     match emmc::init() {
         Ok(sdcard) => {
@@ -94,7 +118,10 @@ pub extern "C" fn kernel_main() -> () {
                     for e in &root_entries {
                         e.print()
                     }
-                    filesystem::virtualfs::get_root().mount(Box::new(root_dir), "");
+                    filesystem::virtualfs::get_root()
+                        .try_lock()
+                        .unwrap()
+                        .mount(Box::new(root_dir), "");
                 }
                 Err(err) => warn!("FAT read failure! {:?}", err),
             }
@@ -114,7 +141,7 @@ pub extern "C" fn kernel_main() -> () {
     }*/
 
     unsafe {
-        let mut appmap1 = memory::application_map::ApplicationMap::new();
+        let mut appmap1 = memory::application_map::ApplicationMap::new().unwrap();
         appmap1.activate();
         let page1 = appmap1.reserve_heap_pages(1).unwrap();
         mmio::write(page1.to_addr() as *mut u32, 42);
@@ -127,7 +154,7 @@ pub extern "C" fn kernel_main() -> () {
 
         mmio::instr_barrier();
 
-        let mut appmap2 = memory::application_map::ApplicationMap::new();
+        let mut appmap2 = memory::application_map::ApplicationMap::new().unwrap();
         let page2 = appmap2.reserve_heap_pages(1).unwrap();
         appmap2.activate();
         println!(
@@ -154,12 +181,32 @@ pub extern "C" fn kernel_main() -> () {
         None => warn!("Random engine timeout"),
     }
 
+    // Initial processes read and write on the UART
+    let console = match filesystem::virtualfs::get_root()
+        .try_lock()
+        .unwrap()
+        .open_file("dev/uart")
+    {
+        Ok(file) => Some(process::FileDescriptor::new(file)),
+        Err(err) => {
+            warn!("Cannot open console: {:?}", err);
+            None
+        }
+    };
+
     scheduler::init();
     match process::Process::new(
         "init".to_owned(),
-        include_bytes!("../target/pi2/release/prgm/init"),
+        Arc::new(ProgramImage::new(
+            include_bytes!("../target/pi2/release/prgm/init").to_vec(),
+        )),
+        &["init".to_owned()],
+        vec![],
     ) {
-        Ok(process) => {
+        Ok(mut process) => {
+            if let Some(console) = &console {
+                process.set_standard_streams(console);
+            }
             scheduler::add_process(Box::new(process));
         }
         Err(err) => {
@@ -169,9 +216,16 @@ pub extern "C" fn kernel_main() -> () {
 
     match process::Process::new(
         "hello_world".to_owned(),
-        include_bytes!("../target/pi2/release/prgm/hello_world"),
+        Arc::new(ProgramImage::new(
+            include_bytes!("../target/pi2/release/prgm/hello_world").to_vec(),
+        )),
+        &["hello_world".to_owned()],
+        vec![],
     ) {
-        Ok(process) => {
+        Ok(mut process) => {
+            if let Some(console) = &console {
+                process.set_standard_streams(console);
+            }
             scheduler::add_process(Box::new(process));
         }
         Err(err) => {
@@ -179,5 +233,26 @@ pub extern "C" fn kernel_main() -> () {
         }
     }
 
+    // Check that the kernel survives applications exhausting the memory
+    #[cfg(feature = "memory_monster_demo")]
+    match process::Process::new(
+        "memory_monster".to_owned(),
+        Arc::new(ProgramImage::new(
+            include_bytes!("../target/pi2/release/prgm/memory_monster").to_vec(),
+        )),
+        &["memory_monster".to_owned()],
+        vec![],
+    ) {
+        Ok(mut process) => {
+            if let Some(console) = &console {
+                process.set_standard_streams(console);
+            }
+            scheduler::add_process(Box::new(process));
+        }
+        Err(err) => {
+            error!("Couldn't launch memory_monster process: {:?}", err);
+        }
+    }
+
     scheduler::start();
 }
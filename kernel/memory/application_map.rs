@@ -1,23 +1,83 @@
 /*!
  * The application memory map is organized as follows:
  * 0x8000_0000 - 0x9FFF_FFFF: Application code, ELF loader place data here
- * 0xA000_0000 - 0xDFFF_FFFF: Application heap, growing up
+ * 0xA000_0000 - 0xBFFF_FFFF: Application heap, growing up
+ * 0xC000_0000 - 0xDFFF_FFFF: Regions mapped with `map_region` (mmap)
  * 0xE000_0000 - 0xFFFF_FFFF: Application stack, growing down
  *
  * Each application has one so each map should be mostly empty.
+ *
+ * Pages of the program and of the heap are only mapped when they are first
+ * accessed: the program segments are recorded with their place in the ELF
+ * image and `load_page` fills them on translation faults. Read-only program
+ * pages are shared by all the processes running the same image (see
+ * `image_cache`).
+ *
+ * Each map gets an ASID (address space identifier) the first time it is
+ * activated, so that switching maps does not require a TLB flush. When the
+ * 256 ASIDs are all used, a new generation starts: the TLBs of all the cores
+ * are flushed once and the maps get new ASIDs when they are next activated.
+ *
+ * The number of pages mapped by an application can be limited with
+ * `set_page_limit`. Pages shared with other maps are counted in each of them.
+ * Maps never take the physical pages reserved for the kernel, so the kernel
+ * keeps running when applications exhaust the memory.
  */
 
+use crate::memory::image_cache::ProgramImage;
 use crate::memory::mmu::*;
 use crate::memory::*;
+use crate::smp;
+use crate::smp::NB_CORES;
+use crate::sync::SpinLock;
 use alloc::boxed::Box;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::mem::size_of;
+use core::ptr;
 use core::ptr::NonNull;
+use core::slice;
 use drivers::mmio;
+use io::Errno;
+
+/// Part of the program image loaded in the program area
+#[derive(Clone)]
+struct Segment {
+    vaddr: usize,
+    mem_size: usize,
+    // The `file_size` bytes at `file_offset` in the image are loaded at
+    // `vaddr`, the rest of the segment is zeroed
+    file_offset: usize,
+    file_size: usize,
+    executable: bool,
+    writable: bool,
+}
+
+impl Segment {
+    fn contains_page(&self, page: PageId) -> bool {
+        page.to_addr() < self.vaddr + self.mem_size && page.to_addr() + PAGE_SIZE > self.vaddr
+    }
+}
 
 pub struct ApplicationMap {
     section_table: Box<SectionTable>,
     last_stack_page: PageId,
     last_heap_page: PageId,
-    asid: Option<u8>,
+    asid: Option<Asid>,
+    // Writable pages currently mapped read-only because their physical page
+    // may be shared with another application map
+    cow_pages: BTreeSet<usize>,
+    // Number of pages of each region of the mmap area, by first page
+    mmap_regions: BTreeMap<usize, usize>,
+    // ELF image of the program and its loadable segments
+    image: Option<Arc<ProgramImage>>,
+    segments: Vec<Segment>,
+    // Number of pages mapped, and the maximum allowed if any
+    nb_pages: usize,
+    page_limit: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -28,98 +88,548 @@ pub enum AppMapError {
     TooManyStackPagesAtOnce,
     HeapLimitReached,
     HeapEmpty,
-    HeapPageAlreadyDeallocated,
+    NotCopyOnWrite,
+    InvalidUserAddress,
+    InvalidMapping,
+    InvalidProtection,
+    MmapAreaFull,
+    PageLimitReached,
+    OutOfMemory,
+}
+
+impl From<AppMapError> for Errno {
+    fn from(err: AppMapError) -> Errno {
+        match err {
+            AppMapError::StackLimitReached
+            | AppMapError::TooManyStackPagesAtOnce
+            | AppMapError::HeapLimitReached
+            | AppMapError::MmapAreaFull
+            | AppMapError::PageLimitReached
+            | AppMapError::OutOfMemory => Errno::OutOfMemory,
+            AppMapError::InvalidProgramAddress => Errno::ExecFormat,
+            AppMapError::HeapEmpty
+            | AppMapError::InvalidMapping
+            | AppMapError::InvalidProtection => Errno::InvalidInput,
+            AppMapError::NoActiveMap
+            | AppMapError::NotCopyOnWrite
+            | AppMapError::InvalidUserAddress => Errno::BadAddress,
+        }
+    }
 }
 
 pub const FIRST_PRGM_PAGE: PageId = PageId(0x800_00);
 pub const FIRST_HEAP_PAGE: PageId = PageId(0xA00_00);
+pub const FIRST_MMAP_PAGE: PageId = PageId(0xC00_00);
 pub const STACK_PAGE_LIMIT: PageId = PageId(0xE00_00);
 pub const AFTER_END_PAGE: PageId = PageId(0x1000_00);
 
-static mut LAST_ASID: u8 = 0;
-static mut ASID_MAPS: [Option<NonNull<ApplicationMap>>; 256] = [None; 256];
-static mut ACTIVE_MAP: Option<NonNull<ApplicationMap>> = None;
+/**
+ * Check that a program segment remains in the range 0x8000_0000 .. 0x9FFF_FFFF
+ * and that its content is inside an image of `image_len` bytes.
+ */
+pub fn check_segment(
+    vaddr: usize,
+    mem_size: usize,
+    file_offset: usize,
+    file_size: usize,
+    image_len: usize,
+) -> Result<(), AppMapError> {
+    let end_addr = vaddr
+        .checked_add(mem_size)
+        .ok_or(AppMapError::InvalidProgramAddress)?;
+    if vaddr < FIRST_PRGM_PAGE.to_addr() || end_addr > FIRST_HEAP_PAGE.to_addr() {
+        return Err(AppMapError::InvalidProgramAddress);
+    }
+    match file_offset.checked_add(min(file_size, mem_size)) {
+        Some(end_offset) if end_offset <= image_len => Ok(()),
+        _ => Err(AppMapError::InvalidProgramAddress),
+    }
+}
+
+/// ASID of a map, only valid during the generation it was given in
+#[derive(Clone, Copy)]
+struct Asid {
+    generation: u64,
+    id: u8,
+}
+
+struct AsidTable {
+    generation: u64,
+    // ASIDs given during the current generation, one bit each
+    used: [u32; 8],
+    next_asid: usize,
+    active_maps: [Option<NonNull<ApplicationMap>>; NB_CORES],
+    // Cores which must flush their TLB before using a new ASID
+    flush_pending: [bool; NB_CORES],
+}
+
+// Maps are owned by processes, the table only points to them while they exist
+unsafe impl Send for AsidTable {}
+
+impl AsidTable {
+    fn is_used(&self, id: u8) -> bool {
+        self.used[id as usize / 32] & (1 << (id % 32)) != 0
+    }
+
+    fn set_used(&mut self, id: u8) {
+        self.used[id as usize / 32] |= 1 << (id % 32);
+    }
+
+    // Give an ASID unused in the current generation
+    fn allocate(&mut self) -> Option<Asid> {
+        let id = (self.next_asid..256)
+            .map(|id| id as u8)
+            .find(|&id| !self.is_used(id))?;
+        self.set_used(id);
+        self.next_asid = id as usize + 1;
+        Some(Asid {
+            generation: self.generation,
+            id,
+        })
+    }
+
+    /**
+     * Start a new generation of ASIDs. The maps active on a core keep their
+     * ASID, the others get a new one when they are next activated.
+     * All the cores must flush their TLB before using a new ASID.
+     */
+    fn new_generation(&mut self) {
+        self.generation += 1;
+        self.used = [0; 8];
+        self.next_asid = 0;
+
+        for core in 0..NB_CORES {
+            if let Some(mut map) = self.active_maps[core] {
+                let map = unsafe { map.as_mut() };
+                if let Some(asid) = map.asid.as_mut() {
+                    asid.generation = self.generation;
+                    self.set_used(asid.id);
+                }
+            }
+        }
+
+        self.flush_pending = [true; NB_CORES];
+        let current_core = smp::core_id();
+        for core in (0..NB_CORES).filter(|&core| core != current_core) {
+            smp::send_tlb_shootdown(core);
+        }
+
+        #[cfg(feature = "trace_app_pages")]
+        info!("New ASID generation {}", self.generation);
+    }
+
+    // Flush the TLB of the current core if a new generation has started
+    fn flush_if_pending(&mut self) {
+        let core = smp::core_id();
+        if self.flush_pending[core] {
+            cache::tlb::invalidate_all_local();
+            mmio::sync_barrier();
+            self.flush_pending[core] = false;
+        }
+    }
+}
+
+static ASID_TABLE: SpinLock<AsidTable> = SpinLock::new(AsidTable {
+    generation: 0,
+    used: [0; 8],
+    next_asid: 0,
+    active_maps: [None; NB_CORES],
+    flush_pending: [false; NB_CORES],
+});
+
+/**
+ * Translate the protection of a region given by an application (a
+ * combination of `abi::PROT_*`) into the flags of its pages.
+ * Pages cannot be write-only, so PROT_WRITE implies PROT_READ.
+ */
+pub fn protection_flags(prot: u32) -> Result<RegionFlags, AppMapError> {
+    if prot & !(abi::PROT_READ | abi::PROT_WRITE | abi::PROT_EXEC) != 0 {
+        return Err(AppMapError::InvalidProtection);
+    }
+
+    let access = if prot & abi::PROT_WRITE != 0 {
+        RegionAccess::Full
+    } else if prot & (abi::PROT_READ | abi::PROT_EXEC) != 0 {
+        RegionAccess::ReadOnly
+    } else {
+        RegionAccess::Forbidden
+    };
+    Ok(RegionFlags {
+        execute: prot & abi::PROT_EXEC != 0,
+        global: false,
+        shareable: true,
+        access,
+        attributes: RegionAttribute::WriteAllocate,
+    })
+}
+
+// Return the map used by the current core
+fn current_map() -> Result<NonNull<ApplicationMap>, AppMapError> {
+    ASID_TABLE.lock().active_maps[smp::core_id()].ok_or(AppMapError::NoActiveMap)
+}
+
+/**
+ * Allocate an empty section table outside of the kernel heap, as the global
+ * allocator would do for `Box::new`, but return OutOfMemory instead of
+ * panicking when memory is exhausted.
+ */
+fn new_section_table() -> Result<Box<mmu::SectionTable>, AppMapError> {
+    // The table takes exactly the two pages given to it by the allocator
+    assert_eq!(size_of::<mmu::SectionTable>(), 2 * PAGE_SIZE);
+    let table_page =
+        physical_alloc::allocate_application_double_page().ok_or(AppMapError::OutOfMemory)?;
+    unsafe {
+        let table = table_page.to_addr() as *mut mmu::SectionTable;
+        ptr::write(table, mmu::SectionTable::new());
+        // Freed by the global allocator, which gives double pages back to
+        // the physical allocator
+        Ok(Box::from_raw(table))
+    }
+}
 
 impl ApplicationMap {
-    pub fn new() -> ApplicationMap {
-        ApplicationMap {
-            section_table: Box::new(mmu::SectionTable::new()),
+    /// Create an empty map, fails with OutOfMemory if memory is exhausted.
+    pub fn new() -> Result<ApplicationMap, AppMapError> {
+        Ok(ApplicationMap {
+            section_table: new_section_table()?,
             last_stack_page: AFTER_END_PAGE,
             last_heap_page: FIRST_HEAP_PAGE,
             asid: None,
+            cow_pages: BTreeSet::new(),
+            mmap_regions: BTreeMap::new(),
+            image: None,
+            segments: Vec::new(),
+            nb_pages: 0,
+            page_limit: None,
+        })
+    }
+
+    /**
+     * Create a copy of this application map.
+     * Physical pages are shared between the two maps. Writable pages are
+     * made read-only in both maps and will be copied on their first write
+     * (see `copy_on_write`). The copy has the same page limit.
+     * Returns OutOfMemory if the section table or the page tables of the copy
+     * cannot be allocated, or if a page already has too many owners.
+     */
+    pub fn fork(&mut self) -> Result<ApplicationMap, AppMapError> {
+        let mut child = ApplicationMap {
+            section_table: new_section_table()?,
+            last_stack_page: self.last_stack_page,
+            last_heap_page: self.last_heap_page,
+            asid: None,
+            cow_pages: self.cow_pages.clone(),
+            mmap_regions: self.mmap_regions.clone(),
+            image: self.image.clone(),
+            segments: self.segments.clone(),
+            nb_pages: self.nb_pages,
+            page_limit: self.page_limit,
+        };
+
+        let mut result = Ok(());
+        for (vpage, phys_page, mut flags) in self.section_table.mapped_pages() {
+            // The pages already shared are released with the copy
+            if !child.section_table.prepare_page_table(vpage)
+                || physical_alloc::share_page(phys_page).is_err()
+            {
+                result = Err(AppMapError::OutOfMemory);
+                break;
+            }
+
+            if flags.access == RegionAccess::Full {
+                // Read-only even for the kernel, so that system calls
+                // writing in application memory also trigger the copy.
+                let page = vpage.to_upper().0;
+                flags.access = RegionAccess::ReadOnly;
+                self.section_table
+                    .set_page_access(vpage, RegionAccess::ReadOnly);
+                self.cow_pages.insert(page);
+                child.cow_pages.insert(page);
+            }
+
+            child.section_table.register_page(vpage, phys_page, &flags);
+        }
+
+        if let Some(asid) = self.asid {
+            cache::tlb::invalidate_asid(asid.id);
         }
+
+        #[cfg(feature = "trace_app_pages")]
+        info!("Forked application map");
+
+        mmio::sync_barrier();
+        result.map(|()| child)
+    }
+
+    /**
+     * Make a copy-on-write page writable again.
+     * If its physical page is still shared, it is replaced by a private copy.
+     */
+    fn copy_on_write(&mut self, page: PageId) -> Result<(), AppMapError> {
+        if !self.cow_pages.contains(&page.0) {
+            return Err(AppMapError::NotCopyOnWrite);
+        }
+
+        let vpage = page.to_lower();
+        let (phys_page, mut flags) = self
+            .section_table
+            .page_flags(vpage)
+            .ok_or(AppMapError::NotCopyOnWrite)?;
+
+        if physical_alloc::is_shared(phys_page) {
+            let new_page =
+                physical_alloc::allocate_application_page().ok_or(AppMapError::OutOfMemory)?;
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    phys_page.to_addr() as *const u8,
+                    new_page.to_addr() as *mut u8,
+                    PAGE_SIZE,
+                );
+            }
+
+            flags.access = RegionAccess::Full;
+            self.section_table.register_page(vpage, new_page, &flags);
+            physical_alloc::deallocate_page(phys_page);
+
+            if flags.execute {
+                cache::invalidate_instr_cache();
+                cache::invalidate_branch_predictor();
+            }
+        } else {
+            // All the other owners already made their own copy
+            self.section_table
+                .set_page_access(vpage, RegionAccess::Full);
+        }
+        self.cow_pages.remove(&page.0);
+
+        if let Some(asid) = self.asid {
+            cache::tlb::invalidate_asid_page(asid.id, page);
+        }
+
+        #[cfg(feature = "trace_app_pages")]
+        info!("Copied application page {} on write", page);
+
+        mmio::sync_barrier();
+        Ok(())
     }
 
     /// Use the application map for the current core
     pub fn activate(&mut self) {
+        let mut asid_table = ASID_TABLE.lock();
         let asid = match self.asid {
-            Some(asid) => asid,
-            None => unsafe {
-                // TODO: Manage the multicore case
-                let asid = LAST_ASID;
-                if let Some(mut old_map) = ASID_MAPS[asid as usize] {
-                    old_map.as_mut().asid = None;
-                    cache::tlb::invalidate_asid(asid);
-                }
-                LAST_ASID = LAST_ASID.wrapping_add(1);
-
-                ASID_MAPS[asid as usize] = Some(NonNull::new_unchecked(self));
+            Some(asid) if asid.generation == asid_table.generation => asid,
+            _ => {
+                let asid = asid_table.allocate().unwrap_or_else(|| {
+                    asid_table.new_generation();
+                    asid_table
+                        .allocate()
+                        .expect("No ASID left in a new generation")
+                });
                 self.asid = Some(asid);
                 asid
-            },
+            }
         };
+        asid_table.flush_if_pending();
 
         unsafe {
-            ACTIVE_MAP = Some(NonNull::new_unchecked(self));
+            asid_table.active_maps[smp::core_id()] = Some(NonNull::new_unchecked(self));
 
             let translation_table = &*self.section_table;
-            mmu::set_application_table(translation_table, asid as u32);
+            mmu::set_application_table(translation_table, asid.id as u32);
         }
     }
 
-    pub fn register_prgm_page(
+    /**
+     * Set the ELF image of the program. Its segments, given with
+     * `add_segment`, are loaded from it.
+     */
+    pub fn set_image(&mut self, image: Arc<ProgramImage>) {
+        self.image = Some(image);
+    }
+
+    /**
+     * Add a segment to the program. Its pages are mapped on their first
+     * access. The image must be set before, see `check_segment` for the
+     * accepted segments.
+     */
+    pub fn add_segment(
         &mut self,
-        vaddr_base: PageId,
+        vaddr: usize,
+        mem_size: usize,
+        file_offset: usize,
+        file_size: usize,
         executable: bool,
         writable: bool,
     ) -> Result<(), AppMapError> {
-        if vaddr_base.0 < FIRST_PRGM_PAGE.0 || vaddr_base.0 >= FIRST_HEAP_PAGE.0 {
-            return Err(AppMapError::InvalidProgramAddress);
+        let image_len = self.image.as_ref().map_or(0, |image| image.data().len());
+        check_segment(vaddr, mem_size, file_offset, file_size, image_len)?;
+        let file_size = min(file_size, mem_size);
+
+        self.segments.push(Segment {
+            vaddr,
+            mem_size,
+            file_offset,
+            file_size,
+            executable,
+            writable,
+        });
+        Ok(())
+    }
+
+    /**
+     * Map a page of the program or of the heap on its first access.
+     * Program pages are filled from the segments containing them, heap pages
+     * are zeroed. Returns InvalidUserAddress if the page belongs to none of
+     * them.
+     */
+    fn load_page(&mut self, page: PageId) -> Result<(), AppMapError> {
+        let vpage = page.to_lower();
+        if self.section_table.page_flags(vpage).is_some() {
+            return Ok(());
         }
 
-        let phys_page = physical_alloc::allocate_page();
+        let in_heap = page.0 >= FIRST_HEAP_PAGE.0 && page.0 < self.last_heap_page.0;
+        // Segments may share their first and last pages
+        let segments: Vec<Segment> = self
+            .segments
+            .iter()
+            .filter(|segment| segment.contains_page(page))
+            .cloned()
+            .collect();
+        if !in_heap && segments.is_empty() {
+            return Err(AppMapError::InvalidUserAddress);
+        }
 
+        let writable = in_heap || segments.iter().any(|segment| segment.writable);
         let flags = RegionFlags {
-            execute: executable,
+            execute: segments.iter().any(|segment| segment.executable),
             global: false,
             shareable: true,
             access: if writable {
                 RegionAccess::Full
             } else {
-                RegionAccess::ReadOnlyKernelWrite
+                RegionAccess::ReadOnly
             },
             attributes: RegionAttribute::WriteAllocate,
         };
 
-        self.section_table
-            .register_page(vaddr_base.to_lower(), phys_page, &flags);
+        self.reserve_page(page)?;
+
+        // Read-only pages may already be loaded by another process
+        if !writable {
+            let image = self.image.as_ref().expect("Program segment without image");
+            if let Some(phys_page) = image.shared_page(page) {
+                self.section_table.register_page(vpage, phys_page, &flags);
+                self.nb_pages += 1;
+                mmio::sync_barrier();
+                return Ok(());
+            }
+        }
+
+        let phys_page =
+            physical_alloc::allocate_application_page().ok_or(AppMapError::OutOfMemory)?;
+        let content = unsafe {
+            ptr::write_bytes(phys_page.to_addr() as *mut u8, 0, PAGE_SIZE);
+            slice::from_raw_parts_mut(phys_page.to_addr() as *mut u8, PAGE_SIZE)
+        };
+
+        let page_addr = page.to_addr();
+        for segment in &segments {
+            let start = max(page_addr, segment.vaddr);
+            let end = min(page_addr + PAGE_SIZE, segment.vaddr + segment.file_size);
+            if start < end {
+                let image = self.image.as_ref().expect("Program segment without image");
+                let file_pos = segment.file_offset + (start - segment.vaddr);
+                content[start - page_addr..end - page_addr]
+                    .copy_from_slice(&image.data()[file_pos..file_pos + (end - start)]);
+            }
+        }
+
+        self.section_table.register_page(vpage, phys_page, &flags);
+        self.nb_pages += 1;
+        if !writable {
+            self.image
+                .as_ref()
+                .unwrap()
+                .add_shared_page(page, phys_page);
+        }
+
+        if flags.execute {
+            // The instructions were written at their physical address
+            cache::invalidate_instr_cache();
+            cache::invalidate_branch_predictor();
+        }
 
         #[cfg(feature = "trace_app_pages")]
-        info!("Allocated application progam page at {}", vaddr_base);
+        info!("Loaded application page {}", page);
 
         mmio::sync_barrier();
         Ok(())
     }
 
+    /// Number of pages mapped by the application
+    pub fn page_count(&self) -> usize {
+        self.nb_pages
+    }
+
+    pub fn page_limit(&self) -> Option<usize> {
+        self.page_limit
+    }
+
+    /**
+     * Limit the number of pages the application can map. Pages already
+     * mapped are kept even if they go beyond the new limit.
+     */
+    pub fn set_page_limit(&mut self, page_limit: Option<usize>) {
+        self.page_limit = page_limit;
+    }
+
+    /**
+     * Check that a new page can be mapped within the page limit and allocate
+     * the page table for it if needed.
+     */
+    fn reserve_page(&mut self, page: PageId) -> Result<(), AppMapError> {
+        if self
+            .page_limit
+            .map_or(false, |limit| self.nb_pages >= limit)
+        {
+            return Err(AppMapError::PageLimitReached);
+        }
+        if !self.section_table.prepare_page_table(page.to_lower()) {
+            return Err(AppMapError::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    // Allocate the physical page of a new page of the map (see `reserve_page`)
+    fn allocate_page(&mut self, page: PageId) -> Result<PageId, AppMapError> {
+        self.reserve_page(page)?;
+        physical_alloc::allocate_application_page().ok_or(AppMapError::OutOfMemory)
+    }
+
+    // Unmap a page if it is mapped and release its physical page
+    fn unmap_page(&mut self, page: PageId) {
+        let vpage = page.to_lower();
+        if let Some((phys_page, _)) = self.section_table.page_flags(vpage) {
+            self.section_table.unregister_page(vpage);
+            self.cow_pages.remove(&page.0);
+            if let Some(asid) = self.asid {
+                cache::tlb::invalidate_asid_page(asid.id, page);
+            }
+            physical_alloc::deallocate_page(phys_page);
+            self.nb_pages -= 1;
+        }
+    }
+
     pub fn add_stack_pages(&mut self, nb: usize) -> Result<(), AppMapError> {
         for _ in 0..nb {
             if self.last_stack_page.0 <= STACK_PAGE_LIMIT.0 {
                 return Err(AppMapError::StackLimitReached);
             }
 
-            self.last_stack_page.0 -= 1;
-
-            let phys_page = physical_alloc::allocate_page();
+            let new_page = PageId(self.last_stack_page.0 - 1);
+            let phys_page = self.allocate_page(new_page)?;
+            self.last_stack_page = new_page;
 
             let flags = RegionFlags {
                 execute: false,
@@ -131,6 +641,7 @@ impl ApplicationMap {
 
             self.section_table
                 .register_page(self.last_stack_page.to_lower(), phys_page, &flags);
+            self.nb_pages += 1;
         }
 
         mmio::sync_barrier();
@@ -138,39 +649,83 @@ impl ApplicationMap {
     }
 
     /**
-     * Add heap memory for the application.
-     * Application heap memory is mapped between 0xA000_0000 and 0xDFFF_FFFF.
-     * This function returns the identifier of the first allocated page.
-     * It returns HeapLimitReached if the requested memory goes above 0xDFFF_FFFF.
+     * Check that the application can access the `len` bytes at `addr`, so
+     * that the kernel can access them on its behalf without faulting.
+     * The stack is grown if the range is just below it. If `write` is set,
+     * the range must be writable by the application and copy-on-write pages
+     * are copied immediately.
      */
-    pub fn reserve_heap_pages(&mut self, nb: usize) -> Result<PageId, AppMapError> {
-        let first_allocated_page = self.last_heap_page;
-        for _ in 0..nb {
-            if self.last_heap_page.0 >= 0xE00_00 {
-                return Err(AppMapError::HeapLimitReached);
-            }
+    pub fn check_user_range(
+        &mut self,
+        addr: usize,
+        len: usize,
+        write: bool,
+    ) -> Result<(), AppMapError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let last_addr = addr
+            .checked_add(len - 1)
+            .ok_or(AppMapError::InvalidUserAddress)?;
+        if addr < FIRST_PRGM_PAGE.to_addr() {
+            return Err(AppMapError::InvalidUserAddress);
+        }
 
-            let phys_page = physical_alloc::allocate_page();
+        let first_page = PageId::from(addr);
+        if first_page.0 >= STACK_PAGE_LIMIT.0 && first_page.0 < self.last_stack_page.0 {
+            self.grow_stack(first_page)?;
+        }
 
-            let flags = RegionFlags {
-                execute: false,
-                global: false,
-                shareable: true,
-                access: RegionAccess::Full,
-                attributes: RegionAttribute::WriteAllocate,
-            };
+        for page in first_page.0..=PageId::from(last_addr).0 {
+            let vpage = PageId(page).to_lower();
+            if self.section_table.translate_addr(vpage.to_addr()).is_none() {
+                self.load_page(PageId(page))?;
+            }
 
-            self.section_table
-                .register_page(self.last_heap_page.to_lower(), phys_page, &flags);
+            let (_, flags) = self
+                .section_table
+                .page_flags(vpage)
+                .ok_or(AppMapError::InvalidUserAddress)?;
+            match flags.access {
+                RegionAccess::Full => (),
+                RegionAccess::ReadOnly if write && self.cow_pages.contains(&page) => {
+                    self.copy_on_write(PageId(page))?
+                }
+                RegionAccess::ReadOnly | RegionAccess::ReadOnlyKernelWrite if !write => (),
+                _ => return Err(AppMapError::InvalidUserAddress),
+            }
+        }
+
+        Ok(())
+    }
 
-            self.last_heap_page.0 += 1;
+    // Add stack pages until the given page is mapped
+    fn grow_stack(&mut self, page: PageId) -> Result<(), AppMapError> {
+        let nb_pages_to_add = self.last_stack_page.0.saturating_sub(page.0);
+        if nb_pages_to_add > 16 {
+            return Err(AppMapError::TooManyStackPagesAtOnce);
         }
 
-        mmio::sync_barrier();
+        self.add_stack_pages(nb_pages_to_add)
+    }
+
+    /**
+     * Add heap memory for the application.
+     * Application heap memory is mapped between 0xA000_0000 and 0xBFFF_FFFF.
+     * This function returns the identifier of the first allocated page.
+     * It returns HeapLimitReached if the requested memory goes above 0xBFFF_FFFF.
+     */
+    pub fn reserve_heap_pages(&mut self, nb: usize) -> Result<PageId, AppMapError> {
+        let first_allocated_page = self.last_heap_page;
+        if FIRST_MMAP_PAGE.0 - self.last_heap_page.0 < nb {
+            return Err(AppMapError::HeapLimitReached);
+        }
+        // The pages are mapped on their first access
+        self.last_heap_page.0 += nb;
 
         #[cfg(feature = "trace_app_pages")]
         info!(
-            "Allocated {} application heap pages at {}",
+            "Reserved {} application heap pages at {}",
             nb, first_allocated_page
         );
 
@@ -184,23 +739,207 @@ impl ApplicationMap {
             }
             self.last_heap_page.0 -= 1;
 
-            let ttbl_addr = self.last_heap_page.to_lower().to_addr();
-            let paddr = self
-                .section_table
-                .translate_addr(ttbl_addr)
-                .ok_or(AppMapError::HeapPageAlreadyDeallocated)?;
+            // Pages that were never accessed are not mapped
+            self.unmap_page(self.last_heap_page);
+        }
+
+        #[cfg(feature = "trace_app_pages")]
+        info!("Deallocated {} application heap pages", nb);
+
+        mmio::sync_barrier();
+        Ok(())
+    }
+
+    // Find `nb_pages` free pages in the mmap area
+    fn find_free_region(&self, nb_pages: usize) -> Result<PageId, AppMapError> {
+        let mut first_page = FIRST_MMAP_PAGE.0;
+        for (&region_start, &region_pages) in &self.mmap_regions {
+            if region_start - first_page >= nb_pages {
+                break;
+            }
+            first_page = region_start + region_pages;
+        }
+
+        if STACK_PAGE_LIMIT.0 - first_page < nb_pages {
+            return Err(AppMapError::MmapAreaFull);
+        }
+        Ok(PageId(first_page))
+    }
+
+    // Check that the pages are inside the mmap area
+    fn check_mmap_range(first_page: PageId, nb_pages: usize) -> Result<(), AppMapError> {
+        if nb_pages == 0
+            || first_page.0 < FIRST_MMAP_PAGE.0
+            || first_page.0 >= STACK_PAGE_LIMIT.0
+            || STACK_PAGE_LIMIT.0 - first_page.0 < nb_pages
+        {
+            return Err(AppMapError::InvalidMapping);
+        }
+        Ok(())
+    }
+
+    /**
+     * Map a region of `nb_pages` zeroed pages in the mmap area.
+     * The region starts at `first_page` if given, these pages must not be
+     * mapped yet. Otherwise the first free range large enough is used.
+     * Returns the first page of the region.
+     */
+    pub fn map_region(
+        &mut self,
+        first_page: Option<PageId>,
+        nb_pages: usize,
+        flags: RegionFlags,
+    ) -> Result<PageId, AppMapError> {
+        let first_page = match first_page {
+            Some(first_page) => {
+                ApplicationMap::check_mmap_range(first_page, nb_pages)?;
+                let end_page = first_page.0 + nb_pages;
+                let overlaps = self
+                    .mmap_regions
+                    .range(..end_page)
+                    .next_back()
+                    .map_or(false, |(&start, &nb)| start + nb > first_page.0);
+                if overlaps {
+                    return Err(AppMapError::InvalidMapping);
+                }
+                first_page
+            }
+            None if nb_pages == 0 => return Err(AppMapError::InvalidMapping),
+            None => self.find_free_region(nb_pages)?,
+        };
+        if self
+            .page_limit
+            .map_or(false, |limit| self.nb_pages + nb_pages > limit)
+        {
+            return Err(AppMapError::PageLimitReached);
+        }
 
+        for page in first_page.0..first_page.0 + nb_pages {
+            let phys_page = match self.allocate_page(PageId(page)) {
+                Ok(phys_page) => phys_page,
+                Err(err) => {
+                    for mapped_page in first_page.0..page {
+                        self.unmap_page(PageId(mapped_page));
+                    }
+                    mmio::sync_barrier();
+                    return Err(err);
+                }
+            };
+            unsafe {
+                ptr::write_bytes(phys_page.to_addr() as *mut u8, 0, PAGE_SIZE);
+            }
             self.section_table
-                .unregister_page(self.last_heap_page.to_lower());
-            if let Some(asid) = self.asid {
-                cache::tlb::invalidate_asid_page(asid, self.last_heap_page);
+                .register_page(PageId(page).to_lower(), phys_page, &flags);
+            self.nb_pages += 1;
+        }
+        self.mmap_regions.insert(first_page.0, nb_pages);
+
+        #[cfg(feature = "trace_app_pages")]
+        info!("Mapped {} application pages at {}", nb_pages, first_page);
+
+        mmio::sync_barrier();
+        Ok(first_page)
+    }
+
+    /**
+     * Give the kernel access to the content of a page of the mmap area,
+     * to fill it after `map_region`.
+     */
+    pub fn region_page_content(&mut self, page: PageId) -> Option<&mut [u8]> {
+        let (phys_page, _) = self.section_table.page_flags(page.to_lower())?;
+        Some(unsafe { slice::from_raw_parts_mut(phys_page.to_addr() as *mut u8, PAGE_SIZE) })
+    }
+
+    /**
+     * Unmap the pages of the mmap area between `first_page` and
+     * `first_page + nb_pages`, regions are split if needed.
+     * Pages of the range that are not mapped are ignored.
+     */
+    pub fn unmap_region(&mut self, first_page: PageId, nb_pages: usize) -> Result<(), AppMapError> {
+        ApplicationMap::check_mmap_range(first_page, nb_pages)?;
+        let range = first_page.0..first_page.0 + nb_pages;
+
+        let overlapping: Vec<(usize, usize)> = self
+            .mmap_regions
+            .range(..range.end)
+            .filter(|&(&start, &nb)| start + nb > range.start)
+            .map(|(&start, &nb)| (start, nb))
+            .collect();
+
+        for (region_start, region_pages) in overlapping {
+            let region_end = region_start + region_pages;
+            self.mmap_regions.remove(&region_start);
+            if region_start < range.start {
+                self.mmap_regions
+                    .insert(region_start, range.start - region_start);
+            }
+            if region_end > range.end {
+                self.mmap_regions.insert(range.end, region_end - range.end);
             }
 
-            physical_alloc::deallocate_page(PageId(paddr / PAGE_SIZE));
+            for page in region_start.max(range.start)..region_end.min(range.end) {
+                self.unmap_page(PageId(page));
+            }
         }
 
         #[cfg(feature = "trace_app_pages")]
-        info!("Deallocated {} application heap pages", nb);
+        info!("Unmapped {} application pages at {}", nb_pages, first_page);
+
+        mmio::sync_barrier();
+        Ok(())
+    }
+
+    /**
+     * Change the protection of the pages of the mmap area between
+     * `first_page` and `first_page + nb_pages`. All of them must be mapped.
+     */
+    pub fn protect_region(
+        &mut self,
+        first_page: PageId,
+        nb_pages: usize,
+        new_flags: RegionFlags,
+    ) -> Result<(), AppMapError> {
+        ApplicationMap::check_mmap_range(first_page, nb_pages)?;
+        let range = first_page.0..first_page.0 + nb_pages;
+
+        let mut mapped_pages = 0;
+        for (&start, &nb) in self.mmap_regions.range(..range.end) {
+            mapped_pages += (start + nb)
+                .min(range.end)
+                .saturating_sub(start.max(range.start));
+        }
+        if mapped_pages != nb_pages {
+            return Err(AppMapError::InvalidMapping);
+        }
+
+        for page in range {
+            let vpage = PageId(page).to_lower();
+            let (phys_page, _) = self
+                .section_table
+                .page_flags(vpage)
+                .ok_or(AppMapError::InvalidMapping)?;
+
+            let mut flags = new_flags;
+            if flags.access == RegionAccess::Full {
+                // Pages still shared with a forked map stay copy-on-write
+                if self.cow_pages.contains(&page) || physical_alloc::is_shared(phys_page) {
+                    flags.access = RegionAccess::ReadOnly;
+                    self.cow_pages.insert(page);
+                }
+            } else {
+                self.cow_pages.remove(&page);
+            }
+
+            self.section_table.register_page(vpage, phys_page, &flags);
+            if let Some(asid) = self.asid {
+                cache::tlb::invalidate_asid_page(asid.id, PageId(page));
+            }
+        }
+
+        if new_flags.execute {
+            cache::invalidate_instr_cache();
+            cache::invalidate_branch_predictor();
+        }
 
         mmio::sync_barrier();
         Ok(())
@@ -213,46 +952,75 @@ impl Drop for ApplicationMap {
         info!("Dropped application map");
 
         // When the application map is destroyed free all the pages.
-        for page in (FIRST_PRGM_PAGE.0..self.last_heap_page.0)
-            .chain(self.last_stack_page.0..AFTER_END_PAGE.0)
-        {
-            let ttbl_addr = (page - 0x800_00) * PAGE_SIZE;
-            if let Some(paddr) = self.section_table.translate_addr(ttbl_addr) {
-                physical_alloc::deallocate_page(PageId(paddr / PAGE_SIZE));
-            }
+        for (_, phys_page, _) in self.section_table.mapped_pages() {
+            physical_alloc::deallocate_page(phys_page);
         }
 
-        unsafe {
-            if ACTIVE_MAP == Some(NonNull::new_unchecked(self)) {
+        let mut asid_table = ASID_TABLE.lock();
+        // A map can only be destroyed while it is not used by other cores
+        let active_map = &mut asid_table.active_maps[smp::core_id()];
+        if *active_map == Some(NonNull::from(&mut *self)) {
+            unsafe {
                 mmu::disable_application_table();
-                ACTIVE_MAP = None;
-            }
-
-            if let Some(asid) = self.asid {
-                cache::tlb::invalidate_asid(asid);
-                ASID_MAPS[asid as usize] = None;
             }
+            *active_map = None;
         }
 
         mmio::sync_barrier();
     }
 }
 
+/**
+ * Stop using any application map on the current core.
+ * Idle cores must not keep a translation table that may be freed by others.
+ */
+pub fn deactivate() {
+    let mut asid_table = ASID_TABLE.lock();
+    unsafe {
+        mmu::disable_application_table();
+    }
+    asid_table.active_maps[smp::core_id()] = None;
+}
+
+/**
+ * Flush the TLB of the current core if ASIDs were reassigned since its last
+ * flush. Called when another core starts a new generation of ASIDs.
+ */
+pub fn flush_stale_tlb() {
+    ASID_TABLE.lock().flush_if_pending();
+}
+
 /**
  * Add memory to the current application stack until the given address is valid.
  * Return error if there are too many (16) pages added at once, or if memory is
  * exhausted.
  */
 pub fn grow_current_stack(addr: usize) -> Result<(), AppMapError> {
-    let page = PageId::from(addr);
-    let mut active_map_ptr = unsafe { ACTIVE_MAP.ok_or(AppMapError::NoActiveMap)? };
+    let mut active_map_ptr = current_map()?;
     let active_map = unsafe { active_map_ptr.as_mut() };
-    let last_stack_page = active_map.last_stack_page;
+    active_map.grow_stack(PageId::from(addr))
+}
 
-    let nb_pages_to_add = last_stack_page.0 - page.0;
-    if nb_pages_to_add > 16 {
-        return Err(AppMapError::TooManyStackPagesAtOnce);
-    }
+/**
+ * Handle a write permission fault in the current application map.
+ * Returns NotCopyOnWrite if the page is not a copy-on-write page.
+ */
+pub fn copy_current_page_on_write(addr: usize) -> Result<(), AppMapError> {
+    let mut active_map_ptr = current_map()?;
+    let active_map = unsafe { active_map_ptr.as_mut() };
+    active_map.copy_on_write(PageId::from(addr))
+}
 
-    active_map.add_stack_pages(nb_pages_to_add)
+/// Map a page of the program or of the heap of the current application (see `load_page`).
+pub fn load_current_page(addr: usize) -> Result<(), AppMapError> {
+    let mut active_map_ptr = current_map()?;
+    let active_map = unsafe { active_map_ptr.as_mut() };
+    active_map.load_page(PageId::from(addr))
+}
+
+/// Check a memory range given by the current application (see `check_user_range`).
+pub fn check_current_user_range(addr: usize, len: usize, write: bool) -> Result<(), AppMapError> {
+    let mut active_map_ptr = current_map()?;
+    let active_map = unsafe { active_map_ptr.as_mut() };
+    active_map.check_user_range(addr, len, write)
 }
@@ -1,8 +1,14 @@
+use super::{PageId, PAGE_SIZE};
+use drivers::mmio;
+
 coproc_reg! {
     ICIALLUIS : p15, c7, 0, c1, 0;
     BPIALLIS  : p15, c7, 0, c1, 6;
+    DCCIMVAC  : p15, c7, 0, c14, 1;
 }
 
+const DATA_CACHE_LINE_SIZE: usize = 64;
+
 pub fn invalidate_instr_cache() {
     unsafe {
         ICIALLUIS::write(0);
@@ -15,6 +21,20 @@ pub fn invalidate_branch_predictor() {
     }
 }
 
+/**
+ * Write back and invalidate the data cache lines of a page, so that it can be
+ * accessed by a core with disabled caches.
+ */
+pub fn clean_invalidate_data_page(page: PageId) {
+    let page_addr = page.to_addr();
+    for addr in (page_addr..page_addr + PAGE_SIZE).step_by(DATA_CACHE_LINE_SIZE) {
+        unsafe {
+            DCCIMVAC::write(addr as u32);
+        }
+    }
+    mmio::sync_barrier();
+}
+
 /**
  * This module contains cache maintenance operations on the
  * Translation Lookaside Buffer.
@@ -24,6 +44,7 @@ pub mod tlb {
     use crate::memory::PageId;
 
     coproc_reg! {
+        TLBIALL    : p15, c8, 0, c7, 0;
         TLBIALLIS  : p15, c8, 0, c3, 0;
         TLBIMVAIS  : p15, c8, 0, c3, 1;
         TLBIASIDIS : p15, c8, 0, c3, 2;
@@ -36,6 +57,13 @@ pub mod tlb {
         }
     }
 
+    /// Invalidate the TLB of the current core only
+    pub fn invalidate_all_local() {
+        unsafe {
+            TLBIALL::write(0);
+        }
+    }
+
     pub fn invalidate_page(vaddr_base: PageId) {
         unsafe {
             TLBIMVAAIS::write(vaddr_base.to_addr() as u32);
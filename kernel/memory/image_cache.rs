@@ -0,0 +1,115 @@
+/*!
+ * Cache of the executable images used by processes.
+ *
+ * Processes running the same program share its image, and the read-only
+ * pages of the program are loaded once and mapped in all of them from the
+ * same physical pages. The cache only keeps weak references: an image and
+ * its pages are freed once no process uses it anymore.
+ *
+ * Images are identified by the identity of their file given by its
+ * filesystem, which changes when the file is modified. The file is only read
+ * if its image is not in the cache.
+ */
+
+use crate::filesystem::FileIdentity;
+use crate::memory::{physical_alloc, PageId};
+use crate::sync::{Once, SpinLock};
+use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use io;
+
+pub struct ProgramImage {
+    data: Vec<u8>,
+    // Physical pages holding the read-only pages of the program, by virtual
+    // page. The image owns one share of each of them.
+    shared_pages: SpinLock<BTreeMap<usize, PageId>>,
+}
+
+impl ProgramImage {
+    /// Create an image that is not registered in the cache.
+    pub fn new(data: Vec<u8>) -> ProgramImage {
+        ProgramImage {
+            data,
+            shared_pages: SpinLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Content of the executable file
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /**
+     * Return the physical page already loaded for the read-only page `page`
+     * of the program, if any. The caller receives a new share of it.
+     * None is also returned if the page already has too many owners, the
+     * caller then loads its own copy.
+     */
+    pub fn shared_page(&self, page: PageId) -> Option<PageId> {
+        let phys_page = *self.shared_pages.lock().get(&page.0)?;
+        physical_alloc::share_page(phys_page).ok()?;
+        Some(phys_page)
+    }
+
+    /**
+     * Register the physical page loaded for the read-only page `page` of the
+     * program, so that other processes map the same one.
+     */
+    pub fn add_shared_page(&self, page: PageId, phys_page: PageId) {
+        let mut shared_pages = self.shared_pages.lock();
+        if !shared_pages.contains_key(&page.0) && physical_alloc::share_page(phys_page).is_ok() {
+            shared_pages.insert(page.0, phys_page);
+        }
+    }
+}
+
+impl Drop for ProgramImage {
+    fn drop(&mut self) {
+        for phys_page in self.shared_pages.lock().values() {
+            physical_alloc::deallocate_page(*phys_page);
+        }
+    }
+}
+
+static IMAGE_CACHE: Once<SpinLock<BTreeMap<FileIdentity, Weak<ProgramImage>>>> = Once::new();
+
+fn image_cache() -> &'static SpinLock<BTreeMap<FileIdentity, Weak<ProgramImage>>> {
+    IMAGE_CACHE.call_once(|| SpinLock::new(BTreeMap::new()))
+}
+
+/**
+ * Return the image of the executable file identified by `identity`.
+ * The cached image is reused if it is still used by a process, otherwise the
+ * content of the file is given by `read`. Files without identity are never
+ * cached.
+ */
+pub fn get_image<F>(identity: Option<FileIdentity>, read: F) -> io::Result<Arc<ProgramImage>>
+where
+    F: FnOnce() -> io::Result<Vec<u8>>,
+{
+    let identity = match identity {
+        Some(identity) => identity,
+        None => return Ok(Arc::new(ProgramImage::new(read()?))),
+    };
+    if let Some(image) = image_cache().lock().get(&identity).and_then(Weak::upgrade) {
+        return Ok(image);
+    }
+
+    // The file is read without holding the cache lock
+    let image = Arc::new(ProgramImage::new(read()?));
+
+    let mut cache = image_cache().lock();
+    // Forget the images that are not used anymore
+    let unused: Vec<FileIdentity> = cache
+        .iter()
+        .filter(|(_, image)| image.upgrade().is_none())
+        .map(|(identity, _)| *identity)
+        .collect();
+    for identity in unused {
+        cache.remove(&identity);
+    }
+
+    cache.insert(identity, Arc::downgrade(&image));
+    Ok(image)
+}
@@ -27,11 +27,14 @@ unsafe impl GlobalAlloc for GlobalKernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // Particular cases for exact page or section demands
         if layout.size() == PAGE_SIZE && layout.align() == PAGE_SIZE {
-            return physical_alloc::allocate_page().to_addr() as *mut u8;
+            return physical_alloc::allocate_page()
+                .map_or(0 as *mut u8, |page| page.to_addr() as *mut u8);
         } else if layout.size() == 2 * PAGE_SIZE && layout.align() == 2 * PAGE_SIZE {
-            return physical_alloc::allocate_double_page().to_addr() as *mut u8;
+            return physical_alloc::allocate_double_page()
+                .map_or(0 as *mut u8, |page| page.to_addr() as *mut u8);
         } else if layout.size() == SECTION_SIZE && layout.align() == SECTION_SIZE {
-            return physical_alloc::allocate_section().to_addr() as *mut u8;
+            return physical_alloc::allocate_section()
+                .map_or(0 as *mut u8, |section| section.to_addr() as *mut u8);
         }
 
         KERNEL_ALLOCATOR
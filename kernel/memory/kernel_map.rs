@@ -9,12 +9,15 @@
  *   0x3F00_0000 - 0x3FFF_FFFF: BCM 2708 peripheral MMIO
  *   0x4000_0000 - 0x400F_FFFF: Quad-A7 peripheral MMIO
  * 0x5000_0000 - 0x6FFF_FFFF: Kernel heap, growing up
- * 0x7000_0000 - 0x7FFF_FFFF: Supervisor (main kernel mode) stack, growing down
+ * 0x7000_0000 - 0x7FFF_FFFF: Supervisor (main kernel mode) stacks, growing down
+ *   64 MiB for each core, the stack of core n starts at 0x8000_0000 - n * 0x0400_0000
  */
 
 use super::mmu::*;
 use super::*;
 use crate::atag;
+use crate::smp;
+use crate::smp::NB_CORES;
 use drivers::mmio;
 
 static mut KERNEL_SECTION_TABLE: SectionTable = SectionTable::new();
@@ -122,6 +125,14 @@ pub fn init() {
         sections.register_page(PageId(0x7FF_F8 + i), PageId(i), &kernel_data_flags);
     }
 
+    enable();
+}
+
+/**
+ * Use the kernel memory map on the current core.
+ * Secondary cores call this function after core 0 has created the map.
+ */
+pub fn enable() {
     unsafe {
         setup_kernel_table(&KERNEL_SECTION_TABLE as *const SectionTable);
     }
@@ -130,23 +141,42 @@ pub fn init() {
 pub const FIRST_HEAP_PAGE: PageId = PageId(0x500_00);
 static mut LAST_HEAP_PAGE: PageId = FIRST_HEAP_PAGE;
 pub const STACK_PAGE_LIMIT: PageId = PageId(0x700_00);
-static mut LAST_STACK_PAGE: PageId = PageId(0x7FF_FA);
 pub const FIRST_APPLICATION_PAGE: PageId = PageId(0x800_00);
+const SVC_STACK_PAGES_BY_CORE: usize = (FIRST_APPLICATION_PAGE.0 - STACK_PAGE_LIMIT.0) / NB_CORES;
+
+// Core 0 starts with the pages registered by `init`, other cores have no page
+static mut LAST_STACK_PAGE: [PageId; NB_CORES] = [
+    PageId(0x7FF_FA),
+    svc_stack_top(1),
+    svc_stack_top(2),
+    svc_stack_top(3),
+];
+
+// First page after the supervisor stack of the given core
+const fn svc_stack_top(core: usize) -> PageId {
+    PageId(FIRST_APPLICATION_PAGE.0 - core * SVC_STACK_PAGES_BY_CORE)
+}
 
 /**
- * Add supervisor stack memory.
- * Supervisor stack is mapped between 0x7000_0000 and 0x7FFF_FFFF.
- * It panics if the requested page goes below 0x7000_0000.
+ * Add supervisor stack memory for the given core.
+ * Supervisor stacks are mapped between 0x7000_0000 and 0x7FFF_FFFF.
+ * It panics if the stack of the core exceeds its 64 MiB, or if memory is
+ * exhausted.
  */
-pub fn add_svc_stack_pages(nb: usize) {
+pub fn add_svc_stack_pages(core: usize, nb: usize) {
     unsafe {
+        let last_stack_page = &mut LAST_STACK_PAGE[core];
         for _ in 0..nb {
-            if LAST_STACK_PAGE.0 <= STACK_PAGE_LIMIT.0 {
-                panic!("Supervisor stack exceeded its maximum size")
+            if last_stack_page.0 <= svc_stack_top(core + 1).0 {
+                panic!(
+                    "Supervisor stack of core {} exceeded its maximum size",
+                    core
+                )
             }
-            LAST_STACK_PAGE.0 -= 1;
+            last_stack_page.0 -= 1;
 
-            let phys_page = physical_alloc::allocate_page();
+            let phys_page =
+                physical_alloc::allocate_page().expect("No memory left for the supervisor stacks");
 
             let flags = RegionFlags {
                 execute: false,
@@ -156,38 +186,47 @@ pub fn add_svc_stack_pages(nb: usize) {
                 attributes: RegionAttribute::WriteAllocate,
             };
 
-            KERNEL_SECTION_TABLE.register_page(LAST_STACK_PAGE, phys_page, &flags);
+            KERNEL_SECTION_TABLE.register_page(*last_stack_page, phys_page, &flags);
         }
 
         mmio::sync_barrier();
 
         #[cfg(feature = "trace_kernel_heap_pages")]
-        info!("Allocated {} supervisor stack pages", nb);
+        info!("Allocated {} supervisor stack pages for core {}", nb, core);
     }
 }
 
 /**
- * Add memory to the stack until the given address is valid.
- * Panics if there are too many (16) pages added at once, or if memory is
- * exhausted.
+ * Add memory to the supervisor stack of the current core until the given
+ * address is valid.
+ * Panics if the address is outside the stack area of the core, if there are
+ * too many (16) pages added at once, or if memory is exhausted.
  */
 pub fn grow_svc_stack(addr: usize) {
     let page = PageId::from(addr);
-    let last_stack_page = unsafe { LAST_STACK_PAGE };
+    let core = smp::core_id();
+    if page.0 < svc_stack_top(core + 1).0 || page.0 >= svc_stack_top(core).0 {
+        panic!(
+            "Supervisor stack fault at {:#x} outside the stack of core {}",
+            addr, core
+        );
+    }
+    let last_stack_page = unsafe { LAST_STACK_PAGE[core] };
 
-    let nb_pages_to_add = last_stack_page.0 - page.0;
+    let nb_pages_to_add = last_stack_page.0.saturating_sub(page.0);
     if nb_pages_to_add > 16 {
         panic!("Trying to add too many pages at once in supervisor stack");
     }
 
-    add_svc_stack_pages(nb_pages_to_add);
+    add_svc_stack_pages(core, nb_pages_to_add);
 }
 
 /**
  * Add heap memory for the kernel.
  * Kernel heap memory is mapped between 0x5000_0000 and 0x6FFF_FFFF.
  * This function returns the identifier of the first allocated page.
- * It panics if the requested memory goes above 0x6FFF_FFFF.
+ * It panics if the requested memory goes above 0x6FFF_FFFF, or if memory is
+ * exhausted.
  */
 pub unsafe fn reserve_heap_pages(nb: usize) -> PageId {
     let first_allocated_page = LAST_HEAP_PAGE;
@@ -196,7 +235,8 @@ pub unsafe fn reserve_heap_pages(nb: usize) -> PageId {
             panic!("Kernel heap exceeded its maximum size")
         }
 
-        let phys_page = physical_alloc::allocate_page();
+        let phys_page =
+            physical_alloc::allocate_page().expect("No memory left for the kernel heap");
 
         let flags = RegionFlags {
             execute: false,
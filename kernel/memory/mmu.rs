@@ -1,4 +1,5 @@
 use super::*;
+use alloc::vec::Vec;
 use drivers::mmio;
 
 #[derive(Clone, Copy)]
@@ -22,7 +23,7 @@ pub enum RegionAttribute {
     WriteAllocate = 0b111,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum RegionAccess {
     /// All accesses generate Permission faults
     Forbidden = 0b000,
@@ -43,6 +44,35 @@ pub enum RegionAccess {
     ReadOnly = 0b111,
 }
 
+impl RegionAccess {
+    fn from_bits(bits: usize) -> RegionAccess {
+        match bits {
+            0b000 => RegionAccess::Forbidden,
+            0b001 => RegionAccess::KernelOnly,
+            0b010 => RegionAccess::ReadOnlyKernelWrite,
+            0b011 => RegionAccess::Full,
+            0b101 => RegionAccess::KernelReadOnly,
+            0b111 => RegionAccess::ReadOnly,
+            _ => panic!("Reserved region access {:#b}", bits),
+        }
+    }
+}
+
+impl RegionAttribute {
+    fn from_bits(bits: usize) -> RegionAttribute {
+        match bits {
+            0b000 => RegionAttribute::StronglyOrdered,
+            0b001 => RegionAttribute::Device,
+            0b100 => RegionAttribute::NonCacheable,
+            0b010 => RegionAttribute::WriteThrough,
+            0b011 => RegionAttribute::WriteBack,
+            0b111 => RegionAttribute::WriteAllocate,
+            _ => panic!("Unsupported region attribute {:#b}", bits),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct RegionFlags {
     pub execute: bool,
     pub global: bool,
@@ -119,13 +149,17 @@ impl SectionTable {
         self.ttbl[vaddr_base.0] = entry;
     }
 
-    fn divide_sections(&mut self, vaddr_base: SectionId) -> *mut PageTable {
+    fn divide_sections(
+        &mut self,
+        vaddr_base: SectionId,
+        allocate_page: fn() -> Option<PageId>,
+    ) -> Option<*mut PageTable> {
         let fst_section_id = (vaddr_base.0 / 4) * 4;
         for section in self.ttbl[fst_section_id..fst_section_id + 4].iter() {
             assert!(section & 0b11 == 0)
         }
 
-        let page_addr = physical_alloc::allocate_page().to_addr();
+        let page_addr = allocate_page()?.to_addr();
         unsafe {
             for offset in 0..PAGE_SIZE as isize {
                 *((page_addr as *mut u8).offset(offset)) = 0
@@ -139,7 +173,7 @@ impl SectionTable {
                 );
             }
 
-            (page_addr as *mut PageTable).offset((vaddr_base.0 % 4) as isize)
+            Some((page_addr as *mut PageTable).offset((vaddr_base.0 % 4) as isize))
         }
     }
 
@@ -161,10 +195,30 @@ impl SectionTable {
         unsafe { (*page_table).unregister_page(PageId(vaddr_base.0 % PAGE_BY_SECTION)) }
     }
 
+    /**
+     * Allocate the page table of the section containing the given page if
+     * it does not exist yet. It is used by application maps and cannot take
+     * the pages reserved for the kernel. Returns false if memory is
+     * exhausted.
+     */
+    pub fn prepare_page_table(&mut self, vaddr_base: PageId) -> bool {
+        let section_id = SectionId(vaddr_base.0 / PAGE_BY_SECTION);
+        self.get_page_table(section_id).is_some()
+            || self
+                .divide_sections(section_id, physical_alloc::allocate_application_page)
+                .is_some()
+    }
+
+    /**
+     * Map a page. It panics if memory is exhausted while allocating its page
+     * table, use `prepare_page_table` first to handle it.
+     */
     pub fn register_page(&mut self, vaddr_base: PageId, paddr_base: PageId, flags: &RegionFlags) {
         let section_id = SectionId(vaddr_base.0 / PAGE_BY_SECTION);
         let page_table = match self.get_page_table(section_id) {
-            None => self.divide_sections(section_id),
+            None => self
+                .divide_sections(section_id, physical_alloc::allocate_page)
+                .expect("No memory left for a page table"),
             Some(ptbl) => ptbl,
         };
 
@@ -173,6 +227,46 @@ impl SectionTable {
         }
     }
 
+    /// Return the physical page and the flags of a mapped page.
+    pub fn page_flags(&self, vaddr_base: PageId) -> Option<(PageId, RegionFlags)> {
+        let section_id = SectionId(vaddr_base.0 / PAGE_BY_SECTION);
+        let page_table = self.get_page_table(section_id)?;
+        unsafe { (*page_table).page_flags(PageId(vaddr_base.0 % PAGE_BY_SECTION)) }
+    }
+
+    /**
+     * Change the access permissions of a mapped page.
+     * The TLB entries of the page must be invalidated afterwards.
+     */
+    pub fn set_page_access(&mut self, vaddr_base: PageId, access: RegionAccess) {
+        let section_id = SectionId(vaddr_base.0 / PAGE_BY_SECTION);
+        let page_table = self
+            .get_page_table(section_id)
+            .expect("cannot change access inside not divided section");
+
+        unsafe { (*page_table).set_page_access(PageId(vaddr_base.0 % PAGE_BY_SECTION), access) }
+    }
+
+    /**
+     * List the mapped pages with their physical page and flags.
+     * Only the sections divided in page tables are visited.
+     */
+    pub fn mapped_pages(&self) -> Vec<(PageId, PageId, RegionFlags)> {
+        let mut pages = Vec::new();
+        for section in 0..self.ttbl.len() {
+            if let Some(page_table) = self.get_page_table(SectionId(section)) {
+                for offset in 0..PAGE_BY_SECTION {
+                    let mapping = unsafe { (*page_table).page_flags(PageId(offset)) };
+                    if let Some((phys_page, flags)) = mapping {
+                        let vpage = PageId(section * PAGE_BY_SECTION + offset);
+                        pages.push((vpage, phys_page, flags));
+                    }
+                }
+            }
+        }
+        pages
+    }
+
     pub fn translate_addr(&self, vaddr: usize) -> Option<usize> {
         let vsection = vaddr / SECTION_SIZE;
         let vpage = (vaddr / PAGE_SIZE) % PAGE_BY_SECTION;
@@ -234,6 +328,35 @@ impl PageTable {
         self.ttbl[vaddr_offset.0] = entry;
     }
 
+    pub fn page_flags(&self, vaddr_offset: PageId) -> Option<(PageId, RegionFlags)> {
+        let entry = self.ttbl[vaddr_offset.0];
+        if entry & 1 << 1 == 0 {
+            return None;
+        }
+
+        let flags = RegionFlags {
+            execute: entry & 1 << 0 == 0,
+            global: entry & 1 << 11 == 0,
+            shareable: entry & 1 << 10 != 0,
+            access: RegionAccess::from_bits((entry >> 4) & 0b011 | (entry >> (9 - 2)) & 0b100),
+            attributes: RegionAttribute::from_bits(
+                (entry >> 2) & 0b011 | (entry >> (6 - 2)) & 0b100,
+            ),
+        };
+        Some((PageId(entry >> 12), flags))
+    }
+
+    pub fn set_page_access(&mut self, vaddr_offset: PageId, access: RegionAccess) {
+        let mut entry = self.ttbl[vaddr_offset.0];
+        assert!(entry & 1 << 1 != 0, "cannot change access of unmapped page");
+
+        entry &= !(0b011 << 4 | 1 << 9);
+        entry |= (access as usize & 0b011) << 4;
+        entry |= (access as usize & 0b100) << (9 - 2);
+
+        self.ttbl[vaddr_offset.0] = entry;
+    }
+
     pub fn translate_page(&self, vaddr: PageId) -> Option<PageId> {
         let entry = self.ttbl[vaddr.0];
         if entry & 1 << 1 == 0 {
@@ -76,7 +76,9 @@ impl fmt::Display for PageId {
 
 pub mod application_map;
 pub mod cache;
+pub mod image_cache;
 pub mod kernel_alloc;
 pub mod kernel_map;
 pub mod mmu;
 pub mod physical_alloc;
+pub mod user_slice;
@@ -1,10 +1,19 @@
 use super::*;
 use crate::atag;
+use crate::sync::SpinLock;
+use core::{mem, ptr, slice};
 
 linker_symbol! {
     static __end;
 }
 
+/**
+ * Number of free pages that application maps cannot take, so that the kernel
+ * heap, the supervisor stacks and the page tables of the kernel can still grow
+ * once applications have exhausted the memory.
+ */
+const KERNEL_RESERVED_PAGES: usize = 256;
+
 #[derive(Clone, Copy)]
 struct Section {
     free_pages: u16, // 0 -> Full, 256 -> Free, other -> Divided
@@ -18,95 +27,120 @@ const FULL_SECTION: Section = Section {
     prev: 0,
 };
 
-static mut SECTIONS: [Section; NUM_SECTION_MAX] = [FULL_SECTION; NUM_SECTION_MAX];
-static mut FST_FREE_SECTION: u16 = 0;
-static mut FST_DIVIDED_SECTION: u16 = 0;
+struct PhysicalAllocator {
+    sections: [Section; NUM_SECTION_MAX],
+    fst_free_section: u16,
+    fst_divided_section: u16,
+    pages: [u16; NUM_PAGES_MAX / 16],
+    // Address of the table giving the number of additional owners of each
+    // allocated page (the processes sharing it and the image cache). A page
+    // is really freed only when it is deallocated by its last owner.
+    // The table is placed in the first sections after the kernel by `init`,
+    // it is too big to be a static of the kernel.
+    page_shares_addr: usize,
+    nb_pages: usize,
+    nb_free_pages: usize,
+}
 
-static mut PAGES: [u16; NUM_PAGES_MAX / 16] = [0; NUM_PAGES_MAX / 16];
+// This lock is also used before the MMU is enabled (see `sync`)
+static ALLOCATOR: SpinLock<PhysicalAllocator> = SpinLock::new(PhysicalAllocator {
+    sections: [FULL_SECTION; NUM_SECTION_MAX],
+    fst_free_section: 0,
+    fst_divided_section: 0,
+    pages: [0; NUM_PAGES_MAX / 16],
+    page_shares_addr: 0,
+    nb_pages: 0,
+    nb_free_pages: 0,
+});
 
-pub fn init() {
-    let mem_size = atag::get_mem_size();
-    let kernel_sections = (linker_symbol!(__end) - 1) / SECTION_SIZE + 1;
-    let num_section = mem_size / SECTION_SIZE;
+impl PhysicalAllocator {
+    fn page_shares(&mut self) -> &mut [u16] {
+        unsafe { slice::from_raw_parts_mut(self.page_shares_addr as *mut u16, self.nb_pages) }
+    }
 
-    unsafe {
-        // From 0 to kernel_sections : SECTIONS[i] = FULL_SECTION
+    fn init(&mut self, kernel_sections: usize, num_section: usize) {
+        // From 0 to kernel_sections : sections[i] = FULL_SECTION
         for i in kernel_sections..num_section - 1 {
-            SECTIONS[i].free_pages = 256;
-            SECTIONS[i].next = (i + 1) as u16;
+            self.sections[i].free_pages = 256;
+            self.sections[i].next = (i + 1) as u16;
         }
-        SECTIONS[num_section - 1].free_pages = 256; // and next = 0
-                                                    // Unavailable sections : SECTIONS[i] = FULL_SECTION
+        self.sections[num_section - 1].free_pages = 256; // and next = 0
+                                                         // Unavailable sections : sections[i] = FULL_SECTION
 
-        FST_FREE_SECTION = kernel_sections as u16;
-        FST_DIVIDED_SECTION = 0;
+        self.fst_free_section = kernel_sections as u16;
+        self.fst_divided_section = 0;
+        self.nb_free_pages = (num_section - kernel_sections) * PAGE_BY_SECTION;
+    }
+
+    // Check that `nb` pages can be allocated for applications
+    fn has_application_pages(&self, nb: usize) -> bool {
+        self.nb_free_pages >= KERNEL_RESERVED_PAGES + nb
     }
-}
 
-pub fn allocate_section() -> SectionId {
-    unsafe {
-        assert!(FST_FREE_SECTION != 0);
+    fn allocate_section(&mut self) -> Option<SectionId> {
+        if self.fst_free_section == 0 {
+            return None;
+        }
 
-        let section_nb = FST_FREE_SECTION as usize;
-        match SECTIONS[section_nb] {
+        let section_nb = self.fst_free_section as usize;
+        match self.sections[section_nb] {
             Section {
                 free_pages: 256,
                 next,
                 ..
             } => {
-                SECTIONS[section_nb].free_pages = 0;
-                FST_FREE_SECTION = next;
+                self.sections[section_nb].free_pages = 0;
+                self.fst_free_section = next;
+                self.nb_free_pages -= PAGE_BY_SECTION;
                 // There is no need to update pages here
             }
             _ => panic!("Section already allocated"),
         }
-        SectionId(section_nb)
+        Some(SectionId(section_nb))
     }
-}
 
-pub fn deallocate_section(i: SectionId) {
-    unsafe {
-        match SECTIONS[i.0] {
+    fn deallocate_section(&mut self, i: SectionId) {
+        match self.sections[i.0] {
             Section { free_pages: 0, .. } => (),
             Section {
                 free_pages: 256, ..
             } => panic!("Deallocating free section {}", i),
             _ => panic!("Deallocating divided section {}", i),
         }
-        SECTIONS[i.0].free_pages = 256;
-        SECTIONS[i.0].next = FST_FREE_SECTION;
-        FST_FREE_SECTION = i.0 as u16;
+        self.sections[i.0].free_pages = 256;
+        self.sections[i.0].next = self.fst_free_section;
+        self.fst_free_section = i.0 as u16;
+        self.nb_free_pages += PAGE_BY_SECTION;
     }
-}
 
-pub fn allocate_page() -> PageId {
-    unsafe {
-        if FST_DIVIDED_SECTION == 0 && FST_FREE_SECTION == 0 {
-            panic!("No more memory available !");
+    fn allocate_page(&mut self) -> Option<PageId> {
+        if self.fst_divided_section == 0 && self.fst_free_section == 0 {
+            return None;
         }
 
-        if FST_DIVIDED_SECTION == 0 {
-            FST_DIVIDED_SECTION = FST_FREE_SECTION;
-            FST_FREE_SECTION = SECTIONS[FST_FREE_SECTION as usize].next;
-            SECTIONS[FST_DIVIDED_SECTION as usize].next = 0;
+        if self.fst_divided_section == 0 {
+            self.fst_divided_section = self.fst_free_section;
+            self.fst_free_section = self.sections[self.fst_free_section as usize].next;
+            self.sections[self.fst_divided_section as usize].next = 0;
         }
 
         for page_group in 0..16 {
-            let page_group_id = FST_DIVIDED_SECTION as usize * 16 + page_group;
-            let page = &mut PAGES[page_group_id];
+            let page_group_id = self.fst_divided_section as usize * 16 + page_group;
+            let page = &mut self.pages[page_group_id];
             if *page != 0xFFFF {
                 for i in 0..16 {
                     if *page & (1 << i) == 0 {
                         let allocated_page = i + 16 * page_group_id;
                         *page |= 1 << i;
+                        self.nb_free_pages -= 1;
 
-                        let section = &mut SECTIONS[FST_DIVIDED_SECTION as usize];
+                        let section = &mut self.sections[self.fst_divided_section as usize];
                         section.free_pages -= 1;
                         if section.free_pages == 0 {
-                            FST_DIVIDED_SECTION = section.next;
+                            self.fst_divided_section = section.next;
                         }
 
-                        return PageId(allocated_page);
+                        return Some(PageId(allocated_page));
                     }
                 }
             }
@@ -114,55 +148,70 @@ pub fn allocate_page() -> PageId {
 
         panic!("FST_DIVIDED_SECTION is already full");
     }
-}
 
-pub fn deallocate_page(page_id: PageId) {
-    unsafe {
+    fn share_page(&mut self, page_id: PageId) -> Result<(), ShareLimitReached> {
+        if self.pages[page_id.0 / 16] & (1 << (page_id.0 % 16)) == 0 {
+            panic!("Sharing page {} which is not allocated", page_id);
+        }
+        let page_shares = &mut self.page_shares()[page_id.0];
+        if *page_shares == 0xFFFF {
+            return Err(ShareLimitReached);
+        }
+        *page_shares += 1;
+        Ok(())
+    }
+
+    fn deallocate_page(&mut self, page_id: PageId) {
+        let page_shares = &mut self.page_shares()[page_id.0];
+        if *page_shares != 0 {
+            // Other owners still use this page
+            *page_shares -= 1;
+            return;
+        }
+
         let section_id = (page_id.0 / PAGE_BY_SECTION) as u16;
-        let section = &mut SECTIONS[section_id as usize];
-        let page_group = &mut PAGES[page_id.0 / 16];
+        let page_group = &mut self.pages[page_id.0 / 16];
         let page_pos = page_id.0 % 16;
 
         if *page_group & (1 << page_pos) == 0 {
             panic!("Page {} is not allocated", page_id);
         }
         *page_group &= !(1 << page_pos);
+        self.nb_free_pages += 1;
 
-        section.free_pages += 1;
+        self.sections[section_id as usize].free_pages += 1;
+        let section = self.sections[section_id as usize];
         if section.free_pages == 1 {
-            section.next = FST_DIVIDED_SECTION;
-            SECTIONS[FST_DIVIDED_SECTION as usize].prev = section_id;
-            FST_DIVIDED_SECTION = section_id;
+            self.sections[section_id as usize].next = self.fst_divided_section;
+            self.sections[self.fst_divided_section as usize].prev = section_id;
+            self.fst_divided_section = section_id;
         } else if section.free_pages == 256 {
             // Remove the section from the divided section list
-            if section_id == FST_DIVIDED_SECTION {
-                FST_DIVIDED_SECTION = section.next;
+            if section_id == self.fst_divided_section {
+                self.fst_divided_section = section.next;
             } else {
-                SECTIONS[section.prev as usize].next = section.next;
+                self.sections[section.prev as usize].next = section.next;
             }
 
             // Add it to free section list
-            section.next = FST_FREE_SECTION;
-            FST_FREE_SECTION = section_id as u16;
+            self.sections[section_id as usize].next = self.fst_free_section;
+            self.fst_free_section = section_id;
         }
     }
-}
 
-pub fn allocate_double_page() -> PageId {
-    unsafe {
-        let mut cur_divided_section = FST_DIVIDED_SECTION;
-        while cur_divided_section != 0 || FST_FREE_SECTION != 0 {
+    fn allocate_double_page(&mut self) -> Option<PageId> {
+        let mut cur_divided_section = self.fst_divided_section;
+        while cur_divided_section != 0 || self.fst_free_section != 0 {
             if cur_divided_section == 0 {
-                FST_DIVIDED_SECTION = FST_FREE_SECTION;
-                cur_divided_section = FST_FREE_SECTION;
-                FST_FREE_SECTION = SECTIONS[FST_FREE_SECTION as usize].next;
-                SECTIONS[cur_divided_section as usize].next = 0;
+                self.fst_divided_section = self.fst_free_section;
+                cur_divided_section = self.fst_free_section;
+                self.fst_free_section = self.sections[self.fst_free_section as usize].next;
+                self.sections[cur_divided_section as usize].next = 0;
             }
 
-            let section = &mut SECTIONS[cur_divided_section as usize];
             for page_group in 0..16 {
                 let page_group_id = cur_divided_section as usize * 16 + page_group;
-                let page = &mut PAGES[page_group_id];
+                let page = &mut self.pages[page_group_id];
                 if *page != 0xFFFF {
                     // This function is mainly used for allocation of application section pages
                     // These must be aligned on 2 pages but misaligned on 4 pages
@@ -171,34 +220,119 @@ pub fn allocate_double_page() -> PageId {
                         if *page & (0b11 << i) == 0 {
                             let allocated_page = i + 16 * page_group_id;
                             *page |= 0b11 << i;
+                            self.nb_free_pages -= 2;
 
-                            section.free_pages -= 2;
+                            self.sections[cur_divided_section as usize].free_pages -= 2;
+                            let section = self.sections[cur_divided_section as usize];
                             if section.free_pages == 0 {
-                                if cur_divided_section == FST_DIVIDED_SECTION {
-                                    FST_DIVIDED_SECTION = section.next;
+                                if cur_divided_section == self.fst_divided_section {
+                                    self.fst_divided_section = section.next;
                                 } else {
-                                    let prev = SECTIONS[cur_divided_section as usize].prev;
-                                    SECTIONS[prev as usize].next = section.next;
-
-                                    let next = SECTIONS[cur_divided_section as usize].next;
-                                    SECTIONS[next as usize].prev = section.prev;
+                                    self.sections[section.prev as usize].next = section.next;
+                                    self.sections[section.next as usize].prev = section.prev;
                                 }
                             }
 
-                            return PageId(allocated_page);
+                            return Some(PageId(allocated_page));
                         }
                     }
                 }
             }
 
-            cur_divided_section = SECTIONS[cur_divided_section as usize].next;
+            cur_divided_section = self.sections[cur_divided_section as usize].next;
         }
 
-        panic!("No more memory available !");
+        None
+    }
+}
+
+pub fn init() {
+    let mem_size = atag::get_mem_size();
+    let kernel_sections = (linker_symbol!(__end) - 1) / SECTION_SIZE + 1;
+    let num_section = mem_size / SECTION_SIZE;
+
+    // The page shares table takes the sections following the kernel, they
+    // are identity mapped like the rest of the memory
+    let nb_pages = num_section * PAGE_BY_SECTION;
+    let table_sections = (nb_pages * mem::size_of::<u16>() - 1) / SECTION_SIZE + 1;
+    let page_shares_addr = kernel_sections * SECTION_SIZE;
+    unsafe { ptr::write_bytes(page_shares_addr as *mut u16, 0, nb_pages) };
+
+    let mut allocator = ALLOCATOR.lock();
+    allocator.page_shares_addr = page_shares_addr;
+    allocator.nb_pages = nb_pages;
+    allocator.init(kernel_sections + table_sections, num_section);
+}
+
+/// Allocate a free section, or return None if there is none left.
+pub fn allocate_section() -> Option<SectionId> {
+    ALLOCATOR.lock().allocate_section()
+}
+
+pub fn deallocate_section(i: SectionId) {
+    ALLOCATOR.lock().deallocate_section(i)
+}
+
+/// Allocate a page, or return None if physical memory is exhausted.
+pub fn allocate_page() -> Option<PageId> {
+    ALLOCATOR.lock().allocate_page()
+}
+
+/**
+ * Allocate a page for an application map. Returns None if this would take
+ * one of the pages reserved for the kernel.
+ */
+pub fn allocate_application_page() -> Option<PageId> {
+    let mut allocator = ALLOCATOR.lock();
+    if !allocator.has_application_pages(1) {
+        return None;
+    }
+    allocator.allocate_page()
+}
+
+/// Error of `share_page` when a page already has the maximum number of owners
+#[derive(Debug)]
+pub struct ShareLimitReached;
+
+/**
+ * Register a new owner for an allocated page.
+ * Each owner must call `deallocate_page` to release it.
+ */
+pub fn share_page(page_id: PageId) -> Result<(), ShareLimitReached> {
+    ALLOCATOR.lock().share_page(page_id)
+}
+
+/// Check if an allocated page has more than one owner.
+pub fn is_shared(page_id: PageId) -> bool {
+    ALLOCATOR.lock().page_shares()[page_id.0] != 0
+}
+
+pub fn deallocate_page(page_id: PageId) {
+    ALLOCATOR.lock().deallocate_page(page_id)
+}
+
+/**
+ * Allocate two contiguous pages, aligned on 2 pages but not on 4 pages.
+ * Returns None if no such pair is free.
+ */
+pub fn allocate_double_page() -> Option<PageId> {
+    ALLOCATOR.lock().allocate_double_page()
+}
+
+/**
+ * Allocate two pages for an application map, like `allocate_double_page`.
+ * Returns None if this would take pages reserved for the kernel.
+ */
+pub fn allocate_application_double_page() -> Option<PageId> {
+    let mut allocator = ALLOCATOR.lock();
+    if !allocator.has_application_pages(2) {
+        return None;
     }
+    allocator.allocate_double_page()
 }
 
 pub fn deallocate_double_page(page_id: PageId) {
-    deallocate_page(page_id);
-    deallocate_page(PageId(page_id.0 + 1));
+    let mut allocator = ALLOCATOR.lock();
+    allocator.deallocate_page(page_id);
+    allocator.deallocate_page(PageId(page_id.0 + 1));
 }
@@ -0,0 +1,91 @@
+/*!
+ * Access to the memory of the current application from system calls.
+ *
+ * Pointers given by applications cannot be trusted: they may point inside
+ * the kernel, to unmapped pages or to read-only pages. Every access goes
+ * through this module that validates the range against the active
+ * application map and reports bad pointers as `Errno::BadAddress`.
+ */
+
+use super::application_map;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::{align_of, size_of};
+use core::{ptr, slice, str};
+use io::Errno;
+
+/// Memory range given by the current application.
+#[derive(Clone, Copy)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    /// The range is not checked until it is accessed.
+    pub fn new(addr: u32, len: u32) -> UserSlice {
+        UserSlice {
+            addr: addr as usize,
+            len: len as usize,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn check(&self, write: bool) -> Result<(), Errno> {
+        application_map::check_current_user_range(self.addr, self.len, write)
+            .map_err(|_| Errno::BadAddress)
+    }
+
+    /**
+     * Borrow the range for reading.
+     * The slice is valid only while the application map stays active and
+     * unmodified.
+     */
+    pub fn as_slice(&self) -> Result<&[u8], Errno> {
+        self.check(false)?;
+        Ok(unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) })
+    }
+
+    /// Borrow the range for writing, with the same restrictions as `as_slice`.
+    pub fn as_mut_slice(&mut self) -> Result<&mut [u8], Errno> {
+        self.check(true)?;
+        Ok(unsafe { slice::from_raw_parts_mut(self.addr as *mut u8, self.len) })
+    }
+
+    /// Copy the range into kernel memory.
+    pub fn to_vec(&self) -> Result<Vec<u8>, Errno> {
+        Ok(self.as_slice()?.to_vec())
+    }
+
+    /// Copy the range into a kernel string, fails if it is not valid UTF-8.
+    pub fn to_string(&self) -> Result<String, Errno> {
+        match str::from_utf8(self.as_slice()?) {
+            Ok(string) => Ok(String::from(string)),
+            Err(_) => Err(Errno::InvalidInput),
+        }
+    }
+}
+
+/// Copy a value from application memory, `addr` must be correctly aligned.
+pub fn copy_from_user<T: Copy>(addr: usize) -> Result<T, Errno> {
+    if addr % align_of::<T>() != 0 {
+        return Err(Errno::BadAddress);
+    }
+    application_map::check_current_user_range(addr, size_of::<T>(), false)
+        .map_err(|_| Errno::BadAddress)?;
+    Ok(unsafe { ptr::read(addr as *const T) })
+}
+
+/// Copy a value to application memory, `addr` must be correctly aligned.
+pub fn copy_to_user<T>(addr: usize, value: T) -> Result<(), Errno> {
+    if addr % align_of::<T>() != 0 {
+        return Err(Errno::BadAddress);
+    }
+    application_map::check_current_user_range(addr, size_of::<T>(), true)
+        .map_err(|_| Errno::BadAddress)?;
+    unsafe { ptr::write(addr as *mut T, value) };
+    Ok(())
+}
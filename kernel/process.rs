@@ -1,15 +1,27 @@
+use crate::filesystem::pipe;
 use crate::filesystem::File;
 use crate::memory;
+use crate::memory::application_map::AFTER_END_PAGE;
+use crate::memory::image_cache::ProgramImage;
+use crate::signal::SignalState;
 use crate::sparse_vec::SparseVec;
 use crate::system_control;
+use crate::timer;
 use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::sync::Arc;
 use alloc::{string::String, vec::Vec};
+use core::cell::RefCell;
+use core::cmp::min;
+use core::mem;
 use core::ptr;
 use drivers::mmio;
 use goblin::elf32;
+use goblin::elf32::program_header::ProgramHeader;
+use io::Errno;
 use plain;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct RegisterContext {
     pub r0: u32,
@@ -53,6 +65,20 @@ impl RegisterContext {
             psr: system_control::ProcessorMode::User as u32,
         }
     }
+
+    /// Check if the saved context was executing Thumb code.
+    pub fn is_thumb(&self) -> bool {
+        self.psr & system_control::PSR_THUMB != 0
+    }
+
+    /**
+     * Move the program counter back on the svc instruction that entered the
+     * kernel, so that the system call is executed again when returning.
+     */
+    pub fn restart_syscall(&mut self) {
+        let svc_size = if self.is_thumb() { 2 } else { 4 };
+        self.pc = (self.pc as usize - svc_size) as *const u32;
+    }
 }
 
 #[derive(PartialEq, Eq)]
@@ -62,23 +88,171 @@ pub enum ProcessState {
     BlockedReading,
     WaitingTimer,
     WaitingChildren,
+    WaitingMutex,
+}
+
+impl ProcessState {
+    /// Encode the state for applications, see `abi::process_state`.
+    pub fn to_raw(&self) -> u32 {
+        use abi::process_state::*;
+        match self {
+            ProcessState::Runnable => RUNNABLE,
+            ProcessState::BlockedReading => BLOCKED_READING,
+            ProcessState::BlockedWriting => BLOCKED_WRITING,
+            ProcessState::WaitingTimer => WAITING_TIMER,
+            ProcessState::WaitingChildren => WAITING_CHILDREN,
+            ProcessState::WaitingMutex => WAITING_MUTEX,
+        }
+    }
+}
+
+/// Processor time used by processes, in core timer ticks
+#[derive(Clone, Copy, Default)]
+pub struct CpuTimes {
+    pub user: u64,
+    pub system: u64,
+}
+
+impl CpuTimes {
+    pub fn add(&mut self, other: CpuTimes) {
+        self.user += other.user;
+        self.system += other.system;
+    }
+
+    pub fn to_usage(self) -> abi::ResourceUsage {
+        abi::ResourceUsage {
+            user_time: timer::core_ticks_to_micros(self.user),
+            system_time: timer::core_ticks_to_micros(self.system),
+        }
+    }
+}
+
+/// How a process ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The process called exit with this code
+    Exited(u32),
+    /// The process was terminated by this signal or by the fault it causes
+    Signaled(u32),
+}
+
+impl ExitStatus {
+    /// Encode the status into two registers: its kind and its value.
+    pub fn to_regs(self) -> (u32, u32) {
+        match self {
+            ExitStatus::Exited(exit_code) => (0, exit_code),
+            ExitStatus::Signaled(signal) => (1, signal),
+        }
+    }
 }
 
 pub struct ChildEvent {
     pub pid: usize,
-    pub exit_code: u32,
+    pub status: ExitStatus,
+}
+
+/**
+ * What remains of a process that has ended until its parent waits for it.
+ * Its memory and files are already released.
+ */
+pub struct Zombie {
+    pub pid: usize,
+    pub parent_pid: usize,
+    pub name: String,
+    pub status: ExitStatus,
+    pub start_time: u64,
+    pub cpu_times: CpuTimes,
+    pub children_cpu_times: CpuTimes,
+}
+
+impl Zombie {
+    pub fn new(process: &Process, status: ExitStatus) -> Zombie {
+        Zombie {
+            pid: process.pid,
+            parent_pid: process.parent_pid,
+            name: process.name.clone(),
+            status,
+            start_time: process.start_time,
+            cpu_times: process.cpu_times,
+            children_cpu_times: process.children_cpu_times,
+        }
+    }
+
+    /// Describe the zombie for applications, see `Process::info`.
+    pub fn info(&self) -> abi::ProcessInfo {
+        let usage = self.cpu_times.to_usage();
+        abi::ProcessInfo {
+            start_time: timer::core_ticks_to_micros(self.start_time),
+            user_time: usage.user_time,
+            system_time: usage.system_time,
+            pid: self.pid as u32,
+            parent_pid: self.parent_pid as u32,
+            state: abi::process_state::ZOMBIE,
+            core: abi::NOT_RUNNING,
+            nice: 0,
+            name: info_name(&self.name),
+        }
+    }
+}
+
+// Name of a process padded or truncated for `abi::ProcessInfo`
+fn info_name(name: &str) -> [u8; abi::PROCESS_NAME_LEN] {
+    let mut info_name = [0; abi::PROCESS_NAME_LEN];
+    let name_len = min(name.len(), abi::PROCESS_NAME_LEN);
+    info_name[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+    info_name
+}
+
+/// Executable file, kept to load the program on demand
+pub type ElfImage = Arc<ProgramImage>;
+
+/// Open file description, shared by all the descriptors referring to it
+pub type OpenFile = Rc<RefCell<Box<File>>>;
+
+#[derive(Clone)]
+pub struct FileDescriptor {
+    pub file: OpenFile,
+    pub close_on_exec: bool,
 }
 
+impl FileDescriptor {
+    pub fn new(file: Box<File>) -> FileDescriptor {
+        FileDescriptor {
+            file: Rc::new(RefCell::new(file)),
+            close_on_exec: false,
+        }
+    }
+}
+
+/// Descriptors of the standard input, output and error streams
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
 pub struct Process {
     pub regs: RegisterContext,
     pub state: ProcessState,
     pub pid: usize,
     pub parent_pid: usize,
     pub children_pid: Vec<usize>,
-    pub child_events: Vec<ChildEvent>,
     pub name: String,
+    pub environment: Vec<(String, String)>,
     pub memory_map: memory::application_map::ApplicationMap,
-    pub file_descriptors: SparseVec<Box<File>>,
+    pub file_descriptors: SparseVec<FileDescriptor>,
+    pub signals: SignalState,
+    /// Nice value, see `scheduler::set_nice`
+    pub nice: i32,
+    /// Current level in the scheduler queues, set by the scheduler
+    pub priority_level: usize,
+    /// Core timer value when the process was added to the scheduler
+    pub start_time: u64,
+    pub cpu_times: CpuTimes,
+    /// Sum of the processor times of the children that have ended
+    pub children_cpu_times: CpuTimes,
+    /// Timer ending the current sleep, cancelled if the process is removed
+    pub wakeup_timer: Option<timer::TimerHandle>,
+    /// Pipe the process is blocked on, left if the process is removed
+    pub pipe_waiter: Option<pipe::PipeWaiter>,
 }
 
 #[derive(Debug)]
@@ -108,8 +282,94 @@ impl From<memory::application_map::AppMapError> for ElfError {
     }
 }
 
+impl From<ElfError> for Errno {
+    fn from(err: ElfError) -> Errno {
+        match err {
+            ElfError::AppMapError(err) => Errno::from(err),
+            _ => Errno::ExecFormat,
+        }
+    }
+}
+
+/// Read the ELF header and check that the file is an ARM 32 bits executable.
+pub fn read_elf_header(file_content: &[u8]) -> Result<elf32::header::Header, ElfError> {
+    let mut elf_header = elf32::header::Header::default();
+    plain::copy_from_bytes(&mut elf_header, file_content)?;
+    if elf_header.e_ident[0..4] != *elf32::header::ELFMAG {
+        return Err(ElfError::InvalidMagicNumber);
+    }
+    if elf_header.e_ident[elf32::header::EI_CLASS] != elf32::header::ELFCLASS32 {
+        return Err(ElfError::InvalidClass);
+    }
+    if elf_header.e_ident[elf32::header::EI_DATA] != elf32::header::ELFDATA2LSB {
+        return Err(ElfError::InvalidDataEncoding);
+    }
+    if elf_header.e_type != elf32::header::ET_EXEC {
+        return Err(ElfError::NotExecutable);
+    }
+    if elf_header.e_machine != elf32::header::EM_ARM {
+        return Err(ElfError::InvalidArchitecture);
+    }
+    if elf_header.e_version != 1 {
+        return Err(ElfError::InvalidVersion);
+    }
+    Ok(elf_header)
+}
+
+/**
+ * Check an executable before changing any process state: its ELF header and
+ * the placement of each loadable segment, in the file and in the program area.
+ * Returns the ELF header and the program headers of the loadable segments.
+ */
+pub fn check_elf(
+    file_content: &[u8],
+) -> Result<(elf32::header::Header, Vec<ProgramHeader>), ElfError> {
+    let elf_header = read_elf_header(file_content)?;
+
+    let prgm_header_tbl = elf_header.e_phoff as usize;
+    let prgm_header_entry_size = elf_header.e_phentsize as usize;
+    let nb_prgm_header_entry = elf_header.e_phnum as usize;
+
+    let mut segments = Vec::new();
+    for entry in 0..nb_prgm_header_entry {
+        let entry_offset = prgm_header_tbl + entry * prgm_header_entry_size;
+
+        let mut prgm_header_entry = ProgramHeader::default();
+        let entry_bytes = file_content
+            .get(entry_offset..)
+            .ok_or(ElfError::FileTooSmall)?;
+        plain::copy_from_bytes(&mut prgm_header_entry, entry_bytes)?;
+
+        if prgm_header_entry.p_type != elf32::program_header::PT_LOAD {
+            continue;
+        }
+
+        let file_offset = prgm_header_entry.p_offset as usize;
+        let file_size = prgm_header_entry.p_filesz as usize;
+        match file_offset.checked_add(file_size) {
+            Some(end_offset) if end_offset <= file_content.len() => (),
+            _ => return Err(ElfError::FileTooSmall),
+        }
+        memory::application_map::check_segment(
+            prgm_header_entry.p_vaddr as usize,
+            prgm_header_entry.p_memsz as usize,
+            file_offset,
+            file_size,
+            file_content.len(),
+        )?;
+
+        segments.push(prgm_header_entry);
+    }
+    Ok((elf_header, segments))
+}
+
 impl Process {
-    pub fn new(name: String, elf_file: &[u8]) -> Result<Process, ElfError> {
+    pub fn new(
+        name: String,
+        elf_file: ElfImage,
+        args: &[String],
+        environment: Vec<(String, String)>,
+    ) -> Result<Process, ElfError> {
         let mut process = Process {
             regs: RegisterContext::new(),
             state: ProcessState::Runnable,
@@ -117,15 +377,73 @@ impl Process {
             pid: 0,
             parent_pid: 0,
             children_pid: vec![],
-            child_events: vec![],
-            memory_map: memory::application_map::ApplicationMap::new(),
+            environment,
+            memory_map: memory::application_map::ApplicationMap::new()?,
             file_descriptors: SparseVec::new(),
+            signals: SignalState::new(),
+            nice: 0,
+            priority_level: 0,
+            start_time: 0,
+            cpu_times: CpuTimes::default(),
+            children_cpu_times: CpuTimes::default(),
+            wakeup_timer: None,
+            pipe_waiter: None,
         };
 
         process.load_elf(elf_file)?;
+        process.push_arguments(args)?;
         Ok(process)
     }
 
+    /**
+     * Duplicate the process.
+     * The child gets a copy-on-write copy of the memory and shares the open
+     * file descriptions of the parent. Its pid must be set by the scheduler.
+     */
+    pub fn fork(&mut self) -> Result<Process, memory::application_map::AppMapError> {
+        Ok(Process {
+            regs: self.regs.clone(),
+            state: ProcessState::Runnable,
+            name: self.name.clone(),
+            pid: 0,
+            parent_pid: self.pid,
+            children_pid: vec![],
+            environment: self.environment.clone(),
+            memory_map: self.memory_map.fork()?,
+            file_descriptors: self.file_descriptors.clone(),
+            signals: self.signals.fork(),
+            nice: self.nice,
+            priority_level: self.priority_level,
+            start_time: 0,
+            cpu_times: CpuTimes::default(),
+            children_cpu_times: CpuTimes::default(),
+            wakeup_timer: None,
+            pipe_waiter: None,
+        })
+    }
+
+    /// Use the same open file as standard input, output and error.
+    pub fn set_standard_streams(&mut self, console: &FileDescriptor) {
+        for descr in STDIN..=STDERR {
+            self.file_descriptors.insert_at(descr, console.clone());
+        }
+    }
+
+    /**
+     * Give the process the standard streams of its parent.
+     * Streams marked as close-on-exec in the parent are not inherited.
+     */
+    pub fn inherit_standard_streams(&mut self, parent: &Process) {
+        for descr in STDIN..=STDERR {
+            match parent.file_descriptors.get(descr) {
+                Some(parent_descr) if !parent_descr.close_on_exec => {
+                    self.file_descriptors.insert_at(descr, parent_descr.clone());
+                }
+                _ => (),
+            }
+        }
+    }
+
     pub fn save_context(&mut self, active_ctx: &RegisterContext) {
         self.regs = active_ctx.clone();
     }
@@ -135,90 +453,140 @@ impl Process {
         self.memory_map.activate();
     }
 
-    fn load_elf(&mut self, file_content: &[u8]) -> Result<(), ElfError> {
-        let mut elf_header = elf32::header::Header::default();
-        plain::copy_from_bytes(&mut elf_header, file_content)?;
-        if elf_header.e_ident[0..4] != *elf32::header::ELFMAG {
-            return Err(ElfError::InvalidMagicNumber);
-        }
-        if elf_header.e_ident[elf32::header::EI_CLASS] != elf32::header::ELFCLASS32 {
-            return Err(ElfError::InvalidClass);
-        }
-        if elf_header.e_ident[elf32::header::EI_DATA] != elf32::header::ELFDATA2LSB {
-            return Err(ElfError::InvalidDataEncoding);
-        }
-        if elf_header.e_type != elf32::header::ET_EXEC {
-            return Err(ElfError::NotExecutable);
+    /**
+     * Replace the program executed by the process.
+     * The pid, the parent, the environment, the blocked signals and the file
+     * descriptors that are not marked as close-on-exec are kept, as well as
+     * the page limit. Signal handlers are reset to their default action.
+     * The new program is loaded in a new memory map, the previous one is only
+     * destroyed once loading succeeded. If this function fails, the process
+     * is left unchanged and its previous map is active again.
+     */
+    pub fn exec(
+        &mut self,
+        name: String,
+        elf_file: ElfImage,
+        args: &[String],
+    ) -> Result<(), ElfError> {
+        let mut memory_map = memory::application_map::ApplicationMap::new()?;
+        memory_map.set_page_limit(self.memory_map.page_limit());
+        let old_map = mem::replace(&mut self.memory_map, memory_map);
+        let old_regs = mem::replace(&mut self.regs, RegisterContext::new());
+
+        if let Err(err) = self
+            .load_elf(elf_file)
+            .and_then(|()| self.push_arguments(args))
+        {
+            // Switch back to the previous map before freeing the new one
+            let new_map = mem::replace(&mut self.memory_map, old_map);
+            self.memory_map.activate();
+            drop(new_map);
+            self.regs = old_regs;
+            return Err(err);
         }
-        if elf_header.e_machine != elf32::header::EM_ARM {
-            return Err(ElfError::InvalidArchitecture);
+
+        // The old map is dropped here and all its pages are freed
+        drop(old_map);
+        self.name = name;
+        self.file_descriptors.retain(|descr| !descr.close_on_exec);
+        self.signals.reset_handlers();
+        Ok(())
+    }
+
+    /// Describe the process for applications, `core` is the core running it.
+    pub fn info(&self, core: Option<usize>) -> abi::ProcessInfo {
+        let usage = self.cpu_times.to_usage();
+        abi::ProcessInfo {
+            start_time: timer::core_ticks_to_micros(self.start_time),
+            user_time: usage.user_time,
+            system_time: usage.system_time,
+            pid: self.pid as u32,
+            parent_pid: self.parent_pid as u32,
+            state: self.state.to_raw(),
+            core: core.map_or(abi::NOT_RUNNING, |core| core as u32),
+            nice: self.nice,
+            name: info_name(&self.name),
         }
-        if elf_header.e_version != 1 {
-            return Err(ElfError::InvalidVersion);
+    }
+
+    fn load_elf(&mut self, file_content: ElfImage) -> Result<(), ElfError> {
+        let (elf_header, segments) = check_elf(file_content.data())?;
+
+        self.memory_map.set_image(file_content);
+        for segment in segments {
+            // The pages of the segment are loaded on their first access
+            self.memory_map.add_segment(
+                segment.p_vaddr as usize,
+                segment.p_memsz as usize,
+                segment.p_offset as usize,
+                segment.p_filesz as usize,
+                segment.p_flags & elf32::program_header::PF_X != 0,
+                segment.p_flags & elf32::program_header::PF_W != 0,
+            )?;
         }
 
-        let entry_point = elf_header.e_entry;
-        let prgm_header_tbl = elf_header.e_phoff as usize;
-        let prgm_header_entry_size = elf_header.e_phentsize as usize;
-        let nb_prgm_header_entry = elf_header.e_phnum as usize;
+        self.memory_map.activate();
+        self.regs.pc = elf_header.e_entry as *const u32;
 
-        for entry in 0..nb_prgm_header_entry {
-            let entry_offset = prgm_header_tbl + entry * prgm_header_entry_size;
+        Ok(())
+    }
 
-            let mut prgm_header_entry = elf32::program_header::ProgramHeader::default();
-            plain::copy_from_bytes(&mut prgm_header_entry, &file_content[entry_offset..])?;
+    /**
+     * Copy the argument vector and the environment of the process on the top
+     * of its stack. The application map of the process must be active.
+     *
+     * The data is laid out in the following order, starting at the initial
+     * stack pointer:
+     * - for each argument: its address and length,
+     * - for each environment variable: the address and length of the key,
+     *   then the address and length of the value,
+     * - the content of all the strings.
+     *
+     * The number of arguments and their address are given in r0 and r1,
+     * the number of environment variables and their address in r2 and r3.
+     */
+    fn push_arguments(&mut self, args: &[String]) -> Result<(), ElfError> {
+        let args_size = 8 * args.len();
+        let env_size = 16 * self.environment.len();
+        let strings_size = args.iter().map(|arg| arg.len()).sum::<usize>()
+            + self
+                .environment
+                .iter()
+                .map(|(key, value)| key.len() + value.len())
+                .sum::<usize>();
 
-            if prgm_header_entry.p_type != elf32::program_header::PT_LOAD {
-                continue;
-            }
+        let nb_pages = (args_size + env_size + strings_size) / memory::PAGE_SIZE + 1;
+        self.memory_map.add_stack_pages(nb_pages)?;
 
-            let vaddr = prgm_header_entry.p_vaddr as usize;
-            let mem_size = prgm_header_entry.p_memsz as usize;
-            let file_offset = prgm_header_entry.p_offset as usize;
-            let file_size = prgm_header_entry.p_filesz as usize;
-            let flags = prgm_header_entry.p_flags;
-
-            // Reserve application program pages and check that all code remain
-            // in the range 0x8000_0000 .. 0x9FFF_FFFF.
-            let vpage = vaddr / memory::PAGE_SIZE;
-            for page in 0..(mem_size + memory::PAGE_SIZE - 1) / memory::PAGE_SIZE {
-                self.memory_map.register_prgm_page(
-                    memory::PageId(vpage + page),
-                    flags & elf32::program_header::PF_X != 0,
-                    flags & elf32::program_header::PF_W != 0,
-                )?;
-            }
-            self.memory_map.activate();
+        let args_addr = (AFTER_END_PAGE.0 - nb_pages) * memory::PAGE_SIZE;
+        let env_addr = args_addr + args_size;
+        let mut string_addr = env_addr + env_size;
 
-            if file_content.len() < file_offset + file_size {
-                return Err(ElfError::FileTooSmall);
+        let mut push_string = |string: &String| -> [u32; 2] {
+            let addr = string_addr;
+            unsafe {
+                ptr::copy_nonoverlapping(string.as_ptr(), addr as *mut u8, string.len());
             }
+            string_addr += string.len();
+            [addr as u32, string.len() as u32]
+        };
 
-            for offset in (0..mem_size).step_by(4) {
-                unsafe {
-                    if offset < file_size {
-                        let file_pos = (file_offset + offset) as isize;
-                        mmio::write(
-                            (vaddr + offset) as *mut u32,
-                            ptr::read_unaligned(
-                                file_content.as_ptr().offset(file_pos) as *const u32
-                            ),
-                        );
-                    } else {
-                        mmio::write((vaddr + offset) as *mut u32, 0);
-                    }
-                }
+        unsafe {
+            for (i, arg) in args.iter().enumerate() {
+                ptr::write((args_addr + 8 * i) as *mut [u32; 2], push_string(arg));
+            }
+            for (i, (key, value)) in self.environment.iter().enumerate() {
+                ptr::write((env_addr + 16 * i) as *mut [u32; 2], push_string(key));
+                ptr::write((env_addr + 16 * i + 8) as *mut [u32; 2], push_string(value));
             }
         }
-
-        // We have updated instructions at their physical address so we must
-        // flush instruction caches.
-        memory::cache::invalidate_instr_cache();
-        memory::cache::invalidate_branch_predictor();
         mmio::sync_barrier();
-        mmio::instr_barrier();
 
-        self.regs.pc = entry_point as *const u32;
+        self.regs.r0 = args.len() as u32;
+        self.regs.r1 = args_addr as u32;
+        self.regs.r2 = self.environment.len() as u32;
+        self.regs.r3 = env_addr as u32;
+        self.regs.sp = args_addr as *const u32;
 
         Ok(())
     }
@@ -1,47 +1,226 @@
-use crate::process::{ChildEvent, Process, ProcessState, RegisterContext};
-use crate::sparse_vec::SparseVec;
+/*!
+ * Process scheduler, a multilevel feedback queue.
+ *
+ * Runnable processes wait in one queue per priority level, level 0 being the
+ * highest. Each process starts at a base level given by its nice value. A
+ * process using its whole time slice moves down one level, and goes back to
+ * its base level when it blocks, so interactive programs stay above CPU bound
+ * ones. All the queued processes periodically return to their base level so
+ * that low levels cannot starve.
+ *
+ * Time slices get longer at low levels, and are scaled by the nice value so
+ * that processes competing at the same level share the CPU by their niceness.
+ *
+ * The processor time of each process is measured with the core timers. Time
+ * before entering the kernel from user mode is user time, and time in the
+ * kernel until the next process is chosen is system time. Time spent in
+ * aborts resolved by the kernel (stack growth, copy on write) is counted as
+ * user time.
+ */
+
+use crate::memory::application_map;
+use crate::process::{ChildEvent, ExitStatus, Process, ProcessState, RegisterContext, Zombie};
+use crate::signal;
+use crate::smp;
+use crate::smp::NB_CORES;
+use crate::sparse_vec::{Handle, SparseVec};
+use crate::sync::{Once, SpinLock, SpinLockGuard};
 use crate::system_control;
 use crate::timer;
 use alloc::boxed::Box;
 use alloc::collections::vec_deque::VecDeque;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::iter;
+use core::mem;
 use drivers::core_timer;
 
+/**
+ * Process identifiers are the raw values of the handles of the processes in
+ * the process table. A pid is therefore never reused by a new process while
+ * it can still be stored somewhere (at least not before its slot has been
+ * reused 2^15 times).
+ */
 type Pid = usize;
 
-struct Scheduler {
-    process_table: SparseVec<Box<Process>>,
-    run_queue: VecDeque<Pid>,
+/// Process adopting the orphans
+pub const INIT_PID: Pid = 0;
+
+/// Number of priority levels, 0 is the highest
+pub const NB_PRIORITY_LEVELS: usize = 8;
+/// Nice value of the processes with the highest priority
+pub const MIN_NICE: i32 = -20;
+/// Nice value of the processes with the lowest priority
+pub const MAX_NICE: i32 = 19;
+
+// Time slice at the highest level for nice 0, in core timer ticks. It doubles
+// every two levels.
+const BASE_TIME_SLICE: u32 = 2_500_000;
+// Number of expired time slices before processes return to their base level
+const PRIORITY_BOOST_PERIOD: usize = 32;
+
+/// Level at which a process with the given nice value starts.
+pub fn base_level(nice: i32) -> usize {
+    ((nice - MIN_NICE) / 10) as usize
+}
+
+// Length of the next time slice of the process
+fn time_slice(process: &Process) -> u32 {
+    let level_slice = BASE_TIME_SLICE << (process.priority_level / 2);
+    // From twice the level slice for MIN_NICE to 1/20th of it for MAX_NICE
+    level_slice / 20 * (-process.nice + 20) as u32
+}
+
+#[derive(Clone, Copy)]
+struct CoreState {
     current_pid: Option<Pid>,
+    // The core must run the scheduler before returning to user mode
     active: bool,
+    // The core has started scheduling processes
+    online: bool,
+    // The time slice of the current process has expired
+    slice_expired: bool,
+    // Core timer value when processor time was last charged to a process
+    accounting_start: u64,
+}
+
+struct Scheduler {
+    process_table: SparseVec<Box<Process>>,
+    // Processes that have ended and whose parent has not waited for them
+    zombies: BTreeMap<Pid, Zombie>,
+    run_queues: [VecDeque<Pid>; NB_PRIORITY_LEVELS],
+    cores: [CoreState; NB_CORES],
+    // Number of time slices expired since the last priority boost
+    expired_slices: usize,
 }
 
-static mut SCHEDULER: Option<Scheduler> = None;
+// The spin lock only protects the tables and queues: processes are used
+// through `get_process` after it is released, and share open files through
+// Rc. All of this relies on the big kernel lock (see `smp`)
+unsafe impl Send for Scheduler {}
+
+impl Scheduler {
+    fn running_core(&self, pid: Pid) -> Option<usize> {
+        self.cores
+            .iter()
+            .position(|core_state| core_state.current_pid == Some(pid))
+    }
+
+    fn enqueue(&mut self, pid: Pid) {
+        let level = self.process_table[Handle::from_raw(pid)].priority_level;
+        self.run_queues[level].push_back(pid);
+    }
+
+    // Take the first process of the highest non empty level
+    fn dequeue(&mut self) -> Option<Pid> {
+        self.run_queues
+            .iter_mut()
+            .find_map(|queue| queue.pop_front())
+    }
+
+    // Returns false if the process was not queued
+    fn remove_from_run_queue(&mut self, pid: Pid) -> bool {
+        for queue in self.run_queues.iter_mut() {
+            if let Some(pos) = queue.iter().position(|x| *x == pid) {
+                queue.remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    /**
+     * Charge the time elapsed since the last call to the process running on
+     * the core, as user time or as system time.
+     */
+    fn charge_time(&mut self, core: usize, user: bool) {
+        let now = core_timer::get_time(core_timer::Virtual);
+        let elapsed = now - mem::replace(&mut self.cores[core].accounting_start, now);
+        if let Some(pid) = self.cores[core].current_pid {
+            let cpu_times = &mut self.process_table[Handle::from_raw(pid)].cpu_times;
+            if user {
+                cpu_times.user += elapsed;
+            } else {
+                cpu_times.system += elapsed;
+            }
+        }
+    }
+
+    // Put all the queued and running processes back to their base level
+    fn boost_priorities(&mut self) {
+        let mut queued = VecDeque::new();
+        for queue in self.run_queues.iter_mut() {
+            queued.append(queue);
+        }
+        let running = self.cores.iter().filter_map(|core| core.current_pid);
+        for pid in running.chain(queued.iter().cloned()) {
+            let process = &mut self.process_table[Handle::from_raw(pid)];
+            process.priority_level = base_level(process.nice);
+        }
+        for pid in queued {
+            self.enqueue(pid);
+        }
+    }
+}
+
+static SCHEDULER: Once<SpinLock<Scheduler>> = Once::new();
 
 pub fn init() {
-    unsafe {
-        SCHEDULER = Some(Scheduler {
+    let core_state = CoreState {
+        current_pid: None,
+        active: false,
+        online: false,
+        slice_expired: false,
+        accounting_start: 0,
+    };
+    SCHEDULER.call_once(|| {
+        SpinLock::new(Scheduler {
             process_table: SparseVec::new(),
-            run_queue: VecDeque::new(),
-            current_pid: None,
-            active: false,
-        });
-    }
+            zombies: BTreeMap::new(),
+            run_queues: Default::default(),
+            cores: [core_state; NB_CORES],
+            expired_slices: 0,
+        })
+    });
     timer::init();
 }
 
+// The lock must not be held while calling other functions of this module
+fn scheduler() -> SpinLockGuard<'static, Scheduler> {
+    SCHEDULER.get().expect("Scheduler not initialized").lock()
+}
+
 fn schedule_timer_handler() {
+    scheduler().cores[smp::core_id()].slice_expired = true;
     plan_scheduling();
-    core_timer::set_remaining_time(core_timer::Virtual, 10_000_000);
+    // Rearmed with the slice of the next process when it is chosen
+    core_timer::set_remaining_time(core_timer::Virtual, BASE_TIME_SLICE);
 }
 
+/// Start scheduling processes on all the cores, called by core 0.
 pub fn start() -> ! {
+    smp::lock_kernel();
     core_timer::register_callback(core_timer::Virtual, schedule_timer_handler, false);
+    smp::init_interrupts();
+    smp::start_secondary_cores();
+    start_core()
+}
+
+/**
+ * Start scheduling processes on the current core.
+ * The kernel lock must be held, it is released before the first process runs.
+ */
+pub fn start_core() -> ! {
+    scheduler().cores[smp::core_id()].online = true;
+
+    // Each core has its own scheduling tick
     core_timer::set_enabled(core_timer::Virtual, true);
-    core_timer::set_remaining_time(core_timer::Virtual, 10_000_000);
+    core_timer::set_remaining_time(core_timer::Virtual, BASE_TIME_SLICE);
     plan_scheduling();
+    smp::unlock_kernel();
     unsafe {
         system_control::set_mode(system_control::ProcessorMode::System);
-        asm!("svc 0" ::: "lr" : "volatile");
+        asm!("svc 0" :: "{r7}"(abi::number::reschedule) : "lr" : "volatile");
     }
     panic!("Scheduler did not start")
 }
@@ -57,115 +236,405 @@ pub fn check_schedule(active_ctx: &mut RegisterContext) {
         return;
     }
 
-    let scheduler = unsafe { SCHEDULER.as_mut().unwrap() };
+    let core = smp::core_id();
+    scheduler().charge_time(core, false);
 
-    if scheduler.active {
-        scheduler.active = false;
-        print!(".");
+    loop {
+        let mut guard = scheduler();
+        let scheduler = &mut *guard;
+        if scheduler.cores[core].active {
+            scheduler.cores[core].active = false;
+            print!(".");
+
+            let slice_expired = mem::replace(&mut scheduler.cores[core].slice_expired, false);
+            if slice_expired {
+                scheduler.expired_slices += 1;
+                if scheduler.expired_slices == PRIORITY_BOOST_PERIOD {
+                    scheduler.expired_slices = 0;
+                    scheduler.boost_priorities();
+                }
+            }
 
-        if let Some(pid) = scheduler.current_pid {
-            let current_process = &mut scheduler.process_table[pid];
+            if let Some(pid) = scheduler.cores[core].current_pid {
+                let current_process = &mut scheduler.process_table[Handle::from_raw(pid)];
 
-            current_process.save_context(active_ctx);
-            if current_process.state == ProcessState::Runnable {
-                scheduler.run_queue.push_back(pid);
+                current_process.save_context(active_ctx);
+                if current_process.state == ProcessState::Runnable {
+                    if slice_expired && current_process.priority_level < NB_PRIORITY_LEVELS - 1 {
+                        current_process.priority_level += 1;
+                    }
+                    scheduler.enqueue(pid);
+                }
             }
-        }
 
-        scheduler.current_pid = scheduler.run_queue.pop_front();
-        match scheduler.current_pid {
-            Some(pid) => {
-                let next_active_process = &mut scheduler.process_table[pid];
-                next_active_process.restore_context(active_ctx);
+            scheduler.cores[core].current_pid = scheduler.dequeue();
+            match scheduler.cores[core].current_pid {
+                Some(pid) => {
+                    let next_active_process = &mut scheduler.process_table[Handle::from_raw(pid)];
+                    next_active_process.restore_context(active_ctx);
+                    let slice = time_slice(next_active_process);
+                    core_timer::set_remaining_time(core_timer::Virtual, slice);
+                }
+                None => {
+                    application_map::deactivate();
+                    active_ctx.pc = idle as *const u32;
+                    active_ctx.psr = system_control::ProcessorMode::System as u32;
+                }
             }
+        }
+
+        let current_pid = scheduler.cores[core].current_pid;
+        drop(guard);
+
+        // Signals are delivered just before returning to user mode. If they
+        // terminate the process, another one must be chosen.
+        match current_pid {
+            Some(pid) if !signal::deliver_pending(pid, active_ctx) => (),
+            _ => break,
+        }
+    }
+}
+
+/**
+ * Charge the time spent in user mode to the current process, called when
+ * entering an exception handler with the context `reg_ctx` of the interrupted
+ * code.
+ */
+pub fn account_kernel_entry(reg_ctx: &RegisterContext) {
+    if reg_ctx.psr & 0b11111 == system_control::ProcessorMode::User as u32 {
+        scheduler().charge_time(smp::core_id(), true);
+    }
+}
+
+/// Run the scheduler on the current core before returning to user mode.
+pub fn plan_scheduling() {
+    scheduler().cores[smp::core_id()].active = true;
+}
+
+/**
+ * Make a core pick the newly queued process `pid`: an idle core if there is
+ * one, or else the core running the process with the lowest priority if it is
+ * lower than the one of `pid`.
+ */
+fn wake_core_for(scheduler: &mut Scheduler, pid: Pid) {
+    // The current core is preferred as it does not need an interrupt
+    let this_core = smp::core_id();
+    let other_cores = (0..NB_CORES).filter(|&core| core != this_core);
+    let mut preempted_core = None;
+    let mut lowest_level = scheduler.process_table[Handle::from_raw(pid)].priority_level;
+
+    for core in iter::once(this_core).chain(other_cores) {
+        let core_state = &mut scheduler.cores[core];
+        if !core_state.online || core_state.active {
+            continue;
+        }
+        match core_state.current_pid {
             None => {
-                active_ctx.pc = idle as *const u32;
-                active_ctx.psr = system_control::ProcessorMode::System as u32;
+                core_state.active = true;
+                if core != this_core {
+                    smp::send_reschedule(core);
+                }
+                return;
+            }
+            Some(running_pid) => {
+                let level = scheduler.process_table[Handle::from_raw(running_pid)].priority_level;
+                if level > lowest_level {
+                    lowest_level = level;
+                    preempted_core = Some(core);
+                }
             }
         }
     }
+
+    match preempted_core {
+        Some(core) if core == this_core => scheduler.cores[core].active = true,
+        Some(core) => smp::send_reschedule(core),
+        None => (),
+    }
 }
 
-pub fn plan_scheduling() {
-    let scheduler = unsafe { SCHEDULER.as_mut().unwrap() };
-    scheduler.active = true;
+/// Return the core currently running the process.
+pub fn running_core(pid: Pid) -> Option<usize> {
+    scheduler().running_core(pid)
+}
+
+/**
+ * Make the core running the process enter the scheduler, so that the process
+ * state and its pending signals are checked.
+ */
+pub fn preempt(pid: Pid) {
+    match running_core(pid) {
+        Some(core) if core == smp::core_id() => plan_scheduling(),
+        Some(core) => smp::send_reschedule(core),
+        None => (),
+    }
 }
 
 pub fn add_process(process: Box<Process>) -> Pid {
-    let scheduler = unsafe { SCHEDULER.as_mut().unwrap() };
+    let mut scheduler = scheduler();
 
-    let pid = scheduler.process_table.insert(process);
-    scheduler.process_table[pid].pid = pid;
+    let pid = scheduler.process_table.insert_handle(process).as_raw();
+    let process = &mut scheduler.process_table[Handle::from_raw(pid)];
+    process.pid = pid;
+    process.priority_level = base_level(process.nice);
+    process.start_time = core_timer::get_time(core_timer::Virtual);
 
-    if scheduler.process_table[pid].state == ProcessState::Runnable {
-        scheduler.run_queue.push_back(pid);
+    if process.state == ProcessState::Runnable {
+        scheduler.enqueue(pid);
+        wake_core_for(&mut scheduler, pid);
     }
 
     pid
 }
 
+/**
+ * Return a process from the process table.
+ * Processes are boxed so the reference stays valid after the scheduler lock
+ * is released, until the process is removed. The scheduler lock does not
+ * protect the process: callers must hold the big kernel lock (see `smp`),
+ * which is the case in all exception handlers.
+ */
 pub fn get_process<'a>(pid: Pid) -> Option<&'a mut Process> {
-    let scheduler = unsafe { SCHEDULER.as_mut().unwrap() };
-    scheduler.process_table.get_mut(pid).map(|x| &mut **x)
+    let mut scheduler = scheduler();
+    scheduler
+        .process_table
+        .get_handle_mut(Handle::from_raw(pid))
+        .map(|x| unsafe { &mut *(&mut **x as *mut Process) })
+}
+
+/// Describe all the existing processes.
+pub fn process_infos() -> Vec<abi::ProcessInfo> {
+    let scheduler = scheduler();
+    scheduler
+        .process_table
+        .iter()
+        .map(|process| process.info(scheduler.running_core(process.pid)))
+        .chain(scheduler.zombies.values().map(Zombie::info))
+        .collect()
 }
 
+/**
+ * Choose the process to kill when physical memory is exhausted: the one
+ * which maps the most pages. Init is never chosen.
+ */
+pub fn out_of_memory_victim() -> Option<Pid> {
+    scheduler()
+        .process_table
+        .iter()
+        .filter(|process| process.pid != INIT_PID)
+        .max_by_key(|process| process.memory_map.page_count())
+        .map(|process| process.pid)
+}
+
+/// Return the pid of the process running on the current core.
 pub fn current_pid() -> Option<Pid> {
-    let scheduler = unsafe { SCHEDULER.as_mut().unwrap() };
-    scheduler.current_pid
+    scheduler().cores[smp::core_id()].current_pid
 }
 
 pub fn current_process<'a>() -> Option<&'a mut Process> {
     get_process(current_pid()?)
 }
 
-pub fn remove_process(pid: Pid) -> Option<Box<Process>> {
-    let scheduler = unsafe { SCHEDULER.as_mut().unwrap() };
+// Remove a process from the process table, or kill it later if it is running
+// on another core
+fn remove_process(pid: Pid) -> Option<Box<Process>> {
+    let mut scheduler = scheduler();
+    let core = smp::core_id();
 
-    let killed_process = scheduler.process_table.remove(pid)?;
-    if killed_process.state == ProcessState::Runnable {
-        suspend_process(pid);
+    if let Some(running_core) = scheduler.running_core(pid) {
+        if running_core != core {
+            // Let the other core end the process, as `signal::send` does
+            warn!(
+                "Process {} to remove is running on core {}",
+                pid, running_core
+            );
+            let process = &mut scheduler.process_table[Handle::from_raw(pid)];
+            process.signals.pending |= 1 << signal::SIGKILL;
+            drop(scheduler);
+            smp::send_reschedule(running_core);
+            return None;
+        }
     }
-    if scheduler.current_pid == Some(pid) {
-        scheduler.current_pid = None;
+
+    if scheduler.cores[core].current_pid == Some(pid) {
+        scheduler.charge_time(core, false);
     }
+    let mut killed_process = scheduler
+        .process_table
+        .remove_handle(Handle::from_raw(pid))?;
+    if scheduler.cores[core].current_pid == Some(pid) {
+        scheduler.cores[core].current_pid = None;
+        scheduler.cores[core].active = true;
+    } else if killed_process.state == ProcessState::Runnable {
+        scheduler.remove_from_run_queue(pid);
+    }
+    drop(scheduler);
 
-    // Reattach all children to process 0 (init)
-    for child_pid in &killed_process.children_pid {
-        scheduler.process_table[*child_pid].parent_pid = 0
+    if let Some(wakeup_timer) = killed_process.wakeup_timer.take() {
+        timer::cancel_timer(wakeup_timer);
+    }
+    if let Some(pipe_waiter) = killed_process.pipe_waiter.take() {
+        pipe_waiter.cancel(pid);
     }
 
     Some(killed_process)
 }
 
-pub fn suspend_process(pid: Pid) {
-    let scheduler = unsafe { SCHEDULER.as_mut().unwrap() };
+/**
+ * End a process. Its memory and files are released and it stays a zombie
+ * until its parent waits for it. Its children are adopted by init.
+ * A process running on another core is only sent SIGKILL and ends when
+ * that core is interrupted, its status is then `Signaled(SIGKILL)`.
+ */
+pub fn terminate_process(pid: Pid, status: ExitStatus) {
+    if let Some(mut process) = remove_process(pid) {
+        let zombie = Zombie::new(&process, status);
+        let children_pid = mem::replace(&mut process.children_pid, Vec::new());
+        drop(process);
+
+        for child_pid in children_pid {
+            adopt_orphan(child_pid);
+        }
+        report_end(zombie);
+    }
+}
+
+// Make init the parent of a process whose parent has ended
+fn adopt_orphan(pid: Pid) {
+    let mut scheduler = scheduler();
+    let ended_orphan = match scheduler
+        .process_table
+        .get_handle_mut(Handle::from_raw(pid))
+    {
+        Some(orphan) => {
+            orphan.parent_pid = INIT_PID;
+            None
+        }
+        None => match scheduler.zombies.remove(&pid) {
+            Some(zombie) => Some(zombie),
+            None => return,
+        },
+    };
+    drop(scheduler);
+
+    if let Some(init) = get_process(INIT_PID) {
+        init.children_pid.push(pid);
+    }
+    if let Some(mut zombie) = ended_orphan {
+        // Init must be told about orphans that have already ended
+        zombie.parent_pid = INIT_PID;
+        report_end(zombie);
+    }
+}
+
+/**
+ * Keep the zombie until its parent waits for it, and notify the parent.
+ * Nobody can wait for the zombie if the parent is gone or ignores SIGCHLD,
+ * it is then reaped right away.
+ */
+fn report_end(zombie: Zombie) {
+    let pid = zombie.pid;
+    let parent_pid = zombie.parent_pid;
+    let parent = match get_process(parent_pid) {
+        Some(parent) => parent,
+        None => return,
+    };
+
+    if parent.signals.is_ignored(signal::SIGCHLD) {
+        parent.children_pid.retain(|&child_pid| child_pid != pid);
+        // The child is reaped now, its times are counted as in reap_child
+        parent.children_cpu_times.add(zombie.cpu_times);
+        parent.children_cpu_times.add(zombie.children_cpu_times);
+        // A waiting parent fails once it has no children left
+        wake_process(parent_pid, ProcessState::WaitingChildren);
+        return;
+    }
+
+    scheduler().zombies.insert(pid, zombie);
+    // The waiting system call is restarted and will find the zombie
+    wake_process(parent_pid, ProcessState::WaitingChildren);
+    let _ = signal::send(parent_pid, signal::SIGCHLD);
+}
 
-    if scheduler.current_pid == Some(pid) {
-        plan_scheduling(); // check_schedule will stop the current process
+/**
+ * Reap an ended child of the process `parent_pid`, the child `target` or any
+ * child if it is None. Returns None if no such child has ended yet.
+ */
+pub fn reap_child(parent_pid: Pid, target: Option<Pid>) -> Option<ChildEvent> {
+    let mut scheduler = scheduler();
+    let pid = scheduler
+        .zombies
+        .values()
+        .find(|zombie| {
+            zombie.parent_pid == parent_pid && target.map_or(true, |target| zombie.pid == target)
+        })?
+        .pid;
+    let zombie = scheduler.zombies.remove(&pid).unwrap();
+    drop(scheduler);
+
+    if let Some(parent) = get_process(parent_pid) {
+        parent.children_pid.retain(|&child_pid| child_pid != pid);
+        parent.children_cpu_times.add(zombie.cpu_times);
+        parent.children_cpu_times.add(zombie.children_cpu_times);
+    }
+    Some(ChildEvent {
+        pid,
+        status: zombie.status,
+    })
+}
+
+pub fn suspend_process(pid: Pid) {
+    if running_core(pid).is_some() {
+        preempt(pid); // check_schedule will stop the process on its core
     } else {
-        let run_queue = scheduler
-            .run_queue
-            .drain(..)
-            .filter(|x| *x != pid)
-            .collect();
-        scheduler.run_queue = run_queue;
+        scheduler().remove_from_run_queue(pid);
     }
 }
 
+/**
+ * Queue a process again after it was blocked.
+ * It goes back to its base level as it did not use all its time slice.
+ */
 pub fn resume_process(pid: Pid) {
-    let scheduler = unsafe { SCHEDULER.as_mut().unwrap() };
-    scheduler.run_queue.push_back(pid);
-}
-
-pub fn send_child_event(reciever_pid: Pid, ev: ChildEvent) {
-    if let Some(reciever) = get_process(reciever_pid) {
-        if reciever.state == ProcessState::WaitingChildren {
-            reciever.regs.r0 = ev.pid as u32;
-            reciever.regs.r1 = ev.exit_code;
-            reciever.state = ProcessState::Runnable;
-            resume_process(reciever_pid);
-        } else {
-            reciever.child_events.push(ev);
+    let mut scheduler = scheduler();
+    let process = &mut scheduler.process_table[Handle::from_raw(pid)];
+    process.priority_level = base_level(process.nice);
+    scheduler.enqueue(pid);
+    wake_core_for(&mut scheduler, pid);
+}
+
+/**
+ * Change the nice value of a process, it is clamped between MIN_NICE and
+ * MAX_NICE. The process is moved to its new base level.
+ */
+pub fn set_nice(pid: Pid, nice: i32) {
+    let mut scheduler = scheduler();
+    let process = &mut scheduler.process_table[Handle::from_raw(pid)];
+    process.nice = nice.max(MIN_NICE).min(MAX_NICE);
+    process.priority_level = base_level(process.nice);
+
+    if scheduler.remove_from_run_queue(pid) {
+        scheduler.enqueue(pid);
+        wake_core_for(&mut scheduler, pid);
+    }
+}
+
+/// Resume the process if it is still waiting in the given state.
+pub fn wake_process(pid: Pid, waiting_state: ProcessState) {
+    if let Some(process) = get_process(pid) {
+        if process.state == waiting_state {
+            process.state = ProcessState::Runnable;
+            resume_process(pid);
         }
     }
 }
+
+/**
+ * Suspend the current process in the given state.
+ * The system call is executed again when the process is resumed.
+ */
+pub fn block_and_restart(pid: Pid, state: ProcessState, reg_ctx: &mut RegisterContext) {
+    get_process(pid).unwrap().state = state;
+    reg_ctx.restart_syscall();
+    suspend_process(pid);
+}
@@ -0,0 +1,330 @@
+/*!
+ * Signals sent to processes.
+ *
+ * Each process has a mask of pending signals, a mask of blocked signals and
+ * an action for each signal. Signals are delivered when the process is about
+ * to return to user mode (see `scheduler::check_schedule`).
+ *
+ * To run a user handler, the current register context and blocked mask are
+ * saved in a `SignalFrame` on the user stack. The handler is then called
+ * with the signal number in r0 and returns to a restorer function that must
+ * call the `sigreturn` system call to restore the saved frame.
+ */
+
+use crate::memory::user_slice::{copy_from_user, copy_to_user};
+use crate::process::{ExitStatus, ProcessState, RegisterContext};
+use crate::scheduler;
+use crate::smp;
+use crate::system_control::{ProcessorMode, PSR_THUMB};
+use crate::timer;
+use core::mem::size_of;
+use io::Errno;
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGABRT: u32 = 6;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+
+pub const NB_SIGNALS: u32 = 32;
+
+// Signals that can be neither caught, ignored nor blocked
+const UNBLOCKABLE_MASK: u32 = 1 << SIGKILL;
+
+// Signals raised by processor faults, which always terminate the process
+const FAULT_MASK: u32 = 1 << SIGILL | 1 << SIGSEGV;
+
+// Mode, interrupt masks and Jazelle bits cannot be restored by sigreturn
+const PSR_PRIVILEGED_MASK: u32 = 1 << 24 | 0b1_1101_1111;
+
+#[derive(Clone, Copy)]
+pub enum SignalAction {
+    Default,
+    Ignore,
+    Handler {
+        handler: u32,
+        restorer: u32,
+        // Signals blocked during the execution of the handler
+        mask: u32,
+    },
+}
+
+pub struct SignalState {
+    pub pending: u32,
+    pub blocked: u32,
+    actions: [SignalAction; NB_SIGNALS as usize],
+}
+
+#[derive(Debug)]
+pub enum SignalError {
+    NoSuchProcess,
+    InvalidSignal,
+    UncatchableSignal,
+    InvalidStack,
+}
+
+impl From<SignalError> for Errno {
+    fn from(err: SignalError) -> Errno {
+        match err {
+            SignalError::NoSuchProcess => Errno::NoSuchProcess,
+            SignalError::InvalidSignal | SignalError::UncatchableSignal => Errno::InvalidInput,
+            SignalError::InvalidStack => Errno::BadAddress,
+        }
+    }
+}
+
+/// Context saved on the user stack while a signal handler runs.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct SignalFrame {
+    regs: RegisterContext,
+    blocked: u32,
+}
+
+fn terminates_by_default(signal: u32) -> bool {
+    signal != SIGCHLD
+}
+
+impl SignalState {
+    pub fn new() -> SignalState {
+        SignalState {
+            pending: 0,
+            blocked: 0,
+            actions: [SignalAction::Default; NB_SIGNALS as usize],
+        }
+    }
+
+    /// Signal state of a forked child: same actions and mask, nothing pending.
+    pub fn fork(&self) -> SignalState {
+        SignalState {
+            pending: 0,
+            blocked: self.blocked,
+            actions: self.actions,
+        }
+    }
+
+    /// User handlers do not survive `exec` but ignored signals remain ignored.
+    pub fn reset_handlers(&mut self) {
+        for action in self.actions.iter_mut() {
+            if let SignalAction::Handler { .. } = action {
+                *action = SignalAction::Default;
+            }
+        }
+    }
+
+    pub fn set_action(
+        &mut self,
+        signal: u32,
+        action: SignalAction,
+    ) -> Result<SignalAction, SignalError> {
+        if signal == 0 || signal >= NB_SIGNALS {
+            return Err(SignalError::InvalidSignal);
+        }
+        if UNBLOCKABLE_MASK & (1 << signal) != 0 {
+            return Err(SignalError::UncatchableSignal);
+        }
+        if let SignalAction::Handler { .. } = action {
+            if FAULT_MASK & (1 << signal) != 0 {
+                // The faulting instruction cannot be resumed after a handler
+                return Err(SignalError::UncatchableSignal);
+            }
+        }
+
+        let old_action = self.actions[signal as usize];
+        self.actions[signal as usize] = action;
+        if !self.is_handled(signal) {
+            // Pending signals that are now ignored are discarded
+            self.pending &= !(1 << signal);
+        }
+        Ok(old_action)
+    }
+
+    pub fn set_blocked(&mut self, mask: u32) {
+        self.blocked = mask & !UNBLOCKABLE_MASK;
+    }
+
+    /// Check if the action of the signal is set to Ignore.
+    pub fn is_ignored(&self, signal: u32) -> bool {
+        match self.actions[signal as usize] {
+            SignalAction::Ignore => true,
+            _ => false,
+        }
+    }
+
+    // Check if receiving the signal has any effect
+    fn is_handled(&self, signal: u32) -> bool {
+        match self.actions[signal as usize] {
+            SignalAction::Default => terminates_by_default(signal),
+            SignalAction::Ignore => false,
+            SignalAction::Handler { .. } => true,
+        }
+    }
+
+    /// Check if a pending signal can be delivered now.
+    pub fn has_deliverable(&self) -> bool {
+        self.pending & !self.blocked != 0
+    }
+
+    fn take_deliverable(&mut self) -> Option<u32> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+
+        let signal = deliverable.trailing_zeros();
+        self.pending &= !(1 << signal);
+        Some(signal)
+    }
+}
+
+/**
+ * Send a signal to a process.
+ * Signal 0 only checks that the process exists and SIGKILL terminates it
+ * immediately, unless it is running on another core which then terminates
+ * it.
+ * A process blocked in a system call is woken up so that it can handle the
+ * signal, the system call is restarted afterwards. A process running on
+ * another core is interrupted. Sleeping processes are woken up early.
+ */
+pub fn send(pid: usize, signal: u32) -> Result<(), SignalError> {
+    if signal >= NB_SIGNALS {
+        return Err(SignalError::InvalidSignal);
+    }
+
+    let process = match scheduler::get_process(pid) {
+        Some(process) => process,
+        None => return Err(SignalError::NoSuchProcess),
+    };
+    if signal == 0 || !process.signals.is_handled(signal) {
+        return Ok(());
+    }
+    let running_elsewhere = match scheduler::running_core(pid) {
+        Some(core) => core != smp::core_id(),
+        None => false,
+    };
+    if signal == SIGKILL && !running_elsewhere {
+        // Do not wait for the process to be scheduled
+        scheduler::terminate_process(pid, ExitStatus::Signaled(SIGKILL));
+        return Ok(());
+    }
+
+    process.signals.pending |= 1 << signal;
+
+    if process.signals.has_deliverable() {
+        match process.state {
+            ProcessState::BlockedReading
+            | ProcessState::BlockedWriting
+            | ProcessState::WaitingChildren
+            | ProcessState::WaitingMutex => {
+                // The program counter was already moved back on the svc
+                if let Some(waiter) = process.pipe_waiter.take() {
+                    waiter.cancel(pid);
+                }
+                process.state = ProcessState::Runnable;
+                scheduler::resume_process(pid);
+            }
+            ProcessState::WaitingTimer => {
+                // The sleep ends early and is not restarted
+                if let Some(wakeup_timer) = process.wakeup_timer.take() {
+                    timer::cancel_timer(wakeup_timer);
+                }
+                process.state = ProcessState::Runnable;
+                scheduler::resume_process(pid);
+            }
+            ProcessState::Runnable if running_elsewhere => scheduler::preempt(pid),
+            ProcessState::Runnable => (),
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Deliver the pending signals of the current process before it returns to
+ * user mode with the context `reg_ctx`.
+ * At most one user handler is started, other signals will be delivered when
+ * it returns. Returns false if the process has been terminated.
+ */
+pub fn deliver_pending(pid: usize, reg_ctx: &mut RegisterContext) -> bool {
+    let process = scheduler::get_process(pid).unwrap();
+
+    while let Some(signal) = process.signals.take_deliverable() {
+        match process.signals.actions[signal as usize] {
+            SignalAction::Default => {
+                if terminates_by_default(signal) {
+                    scheduler::terminate_process(pid, ExitStatus::Signaled(signal));
+                    return false;
+                }
+            }
+            SignalAction::Ignore => (),
+            SignalAction::Handler {
+                handler,
+                restorer,
+                mask,
+            } => {
+                let old_blocked = process.signals.blocked;
+                if let Err(err) = push_frame(reg_ctx, old_blocked) {
+                    error!(
+                        "{}: cannot deliver signal {}: {:?}",
+                        process.name, signal, err
+                    );
+                    scheduler::terminate_process(pid, ExitStatus::Signaled(SIGSEGV));
+                    return false;
+                }
+
+                process
+                    .signals
+                    .set_blocked(old_blocked | mask | (1 << signal));
+
+                reg_ctx.r0 = signal;
+                reg_ctx.lr = restorer as *const u32;
+                reg_ctx.pc = (handler & !1) as *const u32;
+                reg_ctx.psr = ProcessorMode::User as u32 | ((handler & 1) * PSR_THUMB);
+                return true;
+            }
+        }
+    }
+
+    true
+}
+
+fn push_frame(reg_ctx: &mut RegisterContext, blocked: u32) -> Result<(), SignalError> {
+    let frame_addr = (reg_ctx.sp as usize)
+        .checked_sub(size_of::<SignalFrame>())
+        .ok_or(SignalError::InvalidStack)?
+        & !0b111;
+
+    let frame = SignalFrame {
+        regs: *reg_ctx,
+        blocked,
+    };
+    copy_to_user(frame_addr, frame).map_err(|_| SignalError::InvalidStack)?;
+
+    reg_ctx.sp = frame_addr as *const u32;
+    Ok(())
+}
+
+/**
+ * Restore the context saved before running a signal handler.
+ * The frame is expected at the current user stack pointer. The processor
+ * status cannot be used to leave user mode.
+ */
+pub fn restore_frame(pid: usize, reg_ctx: &mut RegisterContext) -> Result<(), SignalError> {
+    let frame: SignalFrame =
+        copy_from_user(reg_ctx.sp as usize).map_err(|_| SignalError::InvalidStack)?;
+
+    *reg_ctx = frame.regs;
+    reg_ctx.psr = (reg_ctx.psr & !PSR_PRIVILEGED_MASK) | ProcessorMode::User as u32;
+
+    let process = scheduler::get_process(pid).unwrap();
+    process.signals.set_blocked(frame.blocked);
+    Ok(())
+}
@@ -0,0 +1,136 @@
+/*!
+ * Multi-core support.
+ *
+ * Core 0 boots the kernel and starts the other cores when the scheduler
+ * starts. All the cores then run the same scheduler, each one with its own
+ * current process, supervisor stack and abort stack.
+ *
+ * Kernel data structures are protected by a big kernel lock. It is taken when
+ * entering an exception handler and released before returning from it. As
+ * kernel code runs with interrupts disabled, a core only holds the lock for
+ * the duration of one handler. The lock is recursive so that aborts inside
+ * the kernel (e.g. supervisor stack growth) do not deadlock.
+ *
+ * Cores wake each other with inter-processor interrupts sent through the
+ * Quad-A7 mailbox 0, and request TLB flushes from each other through
+ * mailbox 1. Mailboxes 2 and 3 pass the boot parameters of the secondary
+ * cores.
+ */
+
+use crate::memory::{application_map, cache, kernel_map, physical_alloc, PAGE_SIZE};
+use crate::scheduler;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use drivers::mailbox;
+
+pub const NB_CORES: usize = 4;
+
+const RESCHEDULE_MAILBOX: u8 = 0;
+const TLB_SHOOTDOWN_MAILBOX: u8 = 1;
+const BOOT_STACK_MAILBOX: u8 = 2;
+const BOOT_ADDRESS_MAILBOX: u8 = 3;
+
+// Number of supervisor stack pages mapped before starting a secondary core
+const INITIAL_SVC_STACK_PAGES: usize = 4;
+
+const NO_OWNER: usize = !0;
+static LOCK_OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+static mut LOCK_DEPTH: [usize; NB_CORES] = [0; NB_CORES];
+
+extern "C" {
+    // Assembly entry point of secondary cores (see boot.s)
+    fn secondary_reset();
+}
+
+/// Return the id of the core executing this function.
+pub fn core_id() -> usize {
+    drivers::get_core_id() as usize
+}
+
+/// Wake the cores waiting in `wait_for_event`.
+pub fn send_event() {
+    unsafe { asm!("dsb\n\tsev" :::: "volatile") }
+}
+
+/// Sleep until another core calls `send_event` or an interrupt occurs.
+pub fn wait_for_event() {
+    unsafe { asm!("wfe" :::: "volatile") }
+}
+
+/**
+ * Take the big kernel lock, waiting for other cores to release it.
+ * If the current core already holds it, only the nesting depth is increased.
+ */
+pub fn lock_kernel() {
+    let core = core_id();
+    if LOCK_OWNER.load(Ordering::Relaxed) != core {
+        while LOCK_OWNER
+            .compare_exchange_weak(NO_OWNER, core, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            wait_for_event();
+        }
+    }
+    unsafe {
+        LOCK_DEPTH[core] += 1;
+    }
+}
+
+/// Release the big kernel lock once for each call to `lock_kernel`.
+pub fn unlock_kernel() {
+    let core = core_id();
+    unsafe {
+        assert!(LOCK_DEPTH[core] > 0, "Kernel lock released but not held");
+        LOCK_DEPTH[core] -= 1;
+        if LOCK_DEPTH[core] == 0 {
+            LOCK_OWNER.store(NO_OWNER, Ordering::Release);
+            send_event();
+        }
+    }
+}
+
+fn reschedule_handler() {
+    mailbox::clear(core_id() as u8, RESCHEDULE_MAILBOX, !0);
+    scheduler::plan_scheduling();
+}
+
+fn tlb_shootdown_handler() {
+    mailbox::clear(core_id() as u8, TLB_SHOOTDOWN_MAILBOX, !0);
+    application_map::flush_stale_tlb();
+}
+
+/// Register the handlers of inter-processor interrupts.
+pub fn init_interrupts() {
+    mailbox::register_callback(RESCHEDULE_MAILBOX, reschedule_handler, false);
+    mailbox::register_callback(TLB_SHOOTDOWN_MAILBOX, tlb_shootdown_handler, false);
+}
+
+/// Interrupt another core so that it runs the scheduler.
+pub fn send_reschedule(core: usize) {
+    mailbox::write(core as u8, RESCHEDULE_MAILBOX, 1);
+}
+
+/// Interrupt another core so that it flushes its stale TLB entries.
+pub fn send_tlb_shootdown(core: usize) {
+    mailbox::write(core as u8, TLB_SHOOTDOWN_MAILBOX, 1);
+}
+
+/**
+ * Start cores 1 to 3.
+ * They enable the kernel memory map and then call `secondary_main`.
+ */
+pub fn start_secondary_cores() {
+    for core in 1..NB_CORES {
+        // The abort stack is identity mapped so it is also used as boot stack
+        // before the MMU is enabled. As the caches of the core are disabled
+        // at that time, no dirty line must remain for this page.
+        let abort_stack =
+            physical_alloc::allocate_page().expect("No memory left for the boot stacks");
+        cache::clean_invalidate_data_page(abort_stack);
+        kernel_map::add_svc_stack_pages(core, INITIAL_SVC_STACK_PAGES);
+
+        let abort_stack_top = abort_stack.to_addr() + PAGE_SIZE;
+        mailbox::write(core as u8, BOOT_STACK_MAILBOX, abort_stack_top as u32);
+        mailbox::write(core as u8, BOOT_ADDRESS_MAILBOX, secondary_reset as u32);
+    }
+    send_event();
+}
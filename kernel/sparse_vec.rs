@@ -1,11 +1,49 @@
 use alloc::vec::Vec;
 use core::ops::{Index, IndexMut};
 
+#[derive(Clone)]
 enum Entry<T> {
     Full(T),
     Empty(usize),
 }
 
+// Low bits of a handle giving the index, the next ones give the generation
+const HANDLE_INDEX_BITS: u32 = 16;
+const HANDLE_INDEX_MASK: usize = (1 << HANDLE_INDEX_BITS) - 1;
+// Handles are kept below 2^31 so that they are never mistaken for error
+// numbers or for special values like !0 when passed to applications
+const GENERATION_MASK: u16 = (1 << (31 - HANDLE_INDEX_BITS)) - 1;
+
+/**
+ * Reference to an item of a `SparseVec`, made of its index and of the
+ * generation of its slot. The generation of a slot changes each time its item
+ * is removed, so a handle kept after the removal of its item never refers to
+ * another item stored later at the same index.
+ *
+ * Handles can be converted to integers smaller than 2^31. The first item
+ * inserted at index 0 has the handle 0.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Handle(usize);
+
+impl Handle {
+    pub const fn from_raw(raw: usize) -> Handle {
+        Handle(raw)
+    }
+
+    pub fn as_raw(self) -> usize {
+        self.0
+    }
+
+    pub fn index(self) -> usize {
+        self.0 & HANDLE_INDEX_MASK
+    }
+
+    fn generation(self) -> u16 {
+        (self.0 >> HANDLE_INDEX_BITS) as u16
+    }
+}
+
 /**
  * A `SparseVec` is a dynamic array of items of type `T` which allow holes
  * inside its structure. New items are stored preferentially in existing holes
@@ -13,9 +51,12 @@ enum Entry<T> {
  * This allows fast deletion without modification of the indices of other
  * items.
  */
+#[derive(Clone)]
 pub struct SparseVec<T> {
     first_empty: usize,
     array: Vec<Entry<T>>,
+    // Generation of each slot, they are kept when the array shrinks
+    generations: Vec<u16>,
 }
 
 impl<T> SparseVec<T> {
@@ -23,6 +64,7 @@ impl<T> SparseVec<T> {
         SparseVec {
             first_empty: 0,
             array: Vec::new(),
+            generations: Vec::new(),
         }
     }
 
@@ -30,6 +72,7 @@ impl<T> SparseVec<T> {
         SparseVec {
             first_empty: 0,
             array: Vec::with_capacity(capacity),
+            generations: Vec::with_capacity(capacity),
         }
     }
 
@@ -41,11 +84,19 @@ impl<T> SparseVec<T> {
         self.array.reserve(additional)
     }
 
+    // Add an entry at the end of the array
+    fn push_entry(&mut self, entry: Entry<T>) {
+        self.array.push(entry);
+        if self.generations.len() < self.array.len() {
+            self.generations.push(0);
+        }
+    }
+
     pub fn insert(&mut self, element: T) -> usize {
         let entry_id = self.first_empty;
         if entry_id == self.array.len() {
             self.first_empty += 1;
-            self.array.push(Entry::Full(element));
+            self.push_entry(Entry::Full(element));
         } else {
             if let Entry::Empty(next_empty) = self.array[entry_id] {
                 self.array[entry_id] = Entry::Full(element);
@@ -57,7 +108,46 @@ impl<T> SparseVec<T> {
         entry_id
     }
 
+    /**
+     * Store an item at the given index, growing the array if needed.
+     * Returns the item previously stored at this index if any.
+     */
+    pub fn insert_at(&mut self, index: usize, element: T) -> Option<T> {
+        use core::mem::replace;
+
+        while self.array.len() <= index {
+            self.push_entry(Entry::Empty(0));
+        }
+
+        let old_entry = replace(&mut self.array[index], Entry::Full(element));
+        self.rebuild_free_list();
+
+        match old_entry {
+            Entry::Full(old_element) => {
+                // Handles to the replaced item must not refer to the new one
+                self.generations[index] = self.generations[index].wrapping_add(1) & GENERATION_MASK;
+                Some(old_element)
+            }
+            Entry::Empty(_) => None,
+        }
+    }
+
+    // Link again all the holes by increasing index
+    fn rebuild_free_list(&mut self) {
+        let mut next_empty = self.array.len();
+        for (index, entry) in self.array.iter_mut().enumerate().rev() {
+            if let Entry::Empty(ref mut next) = entry {
+                *next = next_empty;
+                next_empty = index;
+            }
+        }
+        self.first_empty = next_empty;
+    }
+
     pub fn remove(&mut self, index: usize) -> Option<T> {
+        if index >= self.array.len() {
+            return None;
+        }
         let entry = &mut self.array[index];
         match entry {
             Entry::Full(_) => {
@@ -65,6 +155,7 @@ impl<T> SparseVec<T> {
 
                 let old_entry = replace(entry, Entry::Empty(self.first_empty));
                 self.first_empty = index;
+                self.generations[index] = self.generations[index].wrapping_add(1) & GENERATION_MASK;
 
                 if let Entry::Full(element) = old_entry {
                     Some(element)
@@ -106,10 +197,80 @@ impl<T> SparseVec<T> {
         }
     }
 
+    /// Iterate over the stored items by increasing index.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.array.iter().filter_map(|entry| match entry {
+            Entry::Full(element) => Some(element),
+            Entry::Empty(_) => None,
+        })
+    }
+
+    /// Remove all the items for which `keep` returns false.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut keep: F) {
+        for index in 0..self.array.len() {
+            let kept = match self.array[index] {
+                Entry::Full(ref element) => keep(element),
+                Entry::Empty(_) => true,
+            };
+            if !kept {
+                self.remove(index);
+            }
+        }
+    }
+
     pub fn clear(&mut self) {
+        for (index, entry) in self.array.iter().enumerate() {
+            if let Entry::Full(_) = entry {
+                self.generations[index] = self.generations[index].wrapping_add(1) & GENERATION_MASK;
+            }
+        }
         self.array.clear();
         self.first_empty = 0;
     }
+
+    /**
+     * Insert an item like `insert` but return a handle to it.
+     * Panics if the index does not fit in a handle.
+     */
+    pub fn insert_handle(&mut self, element: T) -> Handle {
+        let index = self.insert(element);
+        assert!(index <= HANDLE_INDEX_MASK, "Too many items for handles");
+        Handle(index | (self.generations[index] as usize) << HANDLE_INDEX_BITS)
+    }
+
+    // Check that the handle refers to the current generation of its slot
+    fn is_current(&self, handle: Handle) -> bool {
+        self.generations.get(handle.index()) == Some(&handle.generation())
+    }
+
+    /// Check if the item referred to by the handle is still stored.
+    pub fn contains_handle(&self, handle: Handle) -> bool {
+        self.is_current(handle) && self.contains(handle.index())
+    }
+
+    pub fn get_handle(&self, handle: Handle) -> Option<&T> {
+        if self.is_current(handle) {
+            self.get(handle.index())
+        } else {
+            None
+        }
+    }
+
+    pub fn get_handle_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        if self.is_current(handle) {
+            self.get_mut(handle.index())
+        } else {
+            None
+        }
+    }
+
+    pub fn remove_handle(&mut self, handle: Handle) -> Option<T> {
+        if self.is_current(handle) {
+            self.remove(handle.index())
+        } else {
+            None
+        }
+    }
 }
 
 impl<T> Index<usize> for SparseVec<T> {
@@ -131,3 +292,34 @@ impl<T> IndexMut<usize> for SparseVec<T> {
         }
     }
 }
+
+impl<T> Index<Handle> for SparseVec<T> {
+    type Output = T;
+
+    fn index(&self, handle: Handle) -> &T {
+        self.get_handle(handle).expect("stale or invalid handle")
+    }
+}
+
+impl<T> IndexMut<Handle> for SparseVec<T> {
+    fn index_mut(&mut self, handle: Handle) -> &mut T {
+        self.get_handle_mut(handle)
+            .expect("stale or invalid handle")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_out_of_range() {
+        let mut vec = SparseVec::new();
+        let index = vec.insert(42);
+
+        assert_eq!(vec.remove(5000), None);
+        assert_eq!(vec.remove(index + 1), None);
+        assert_eq!(vec.remove(index), Some(42));
+        assert_eq!(vec.remove(index), None);
+    }
+}
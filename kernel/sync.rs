@@ -0,0 +1,258 @@
+/*!
+ * Synchronization primitives protecting kernel global state.
+ *
+ * - `SpinLock` is held for short sections that may run in interrupt handlers
+ *   or on several cores at once. IRQs are masked while it is held so that a
+ *   handler cannot try to take a lock already held by the code it interrupted.
+ *   It is not recursive: an abort taken while the lock is held (e.g. on
+ *   supervisor stack growth) must not take it again, this panics instead of
+ *   deadlocking.
+ * - `Mutex` may be contended by processes inside system calls. A process
+ *   finding it locked is suspended and its system call restarted once the
+ *   mutex is released.
+ * - `Once` holds a value initialized at boot and then only read.
+ *
+ * Atomic operations compile to exclusive accesses (ldrex/strex). These do not
+ * work on normal memory before the MMU is enabled, so spin locks are not
+ * acquired at that time. Only the boot core runs then.
+ */
+
+use crate::process::{ProcessState, RegisterContext};
+use crate::scheduler;
+use crate::smp;
+use crate::system_control;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use drivers::interrupts;
+
+const NO_OWNER: usize = !0;
+
+pub struct SpinLock<T> {
+    // Core holding the lock
+    owner: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    // The lock was really taken (MMU enabled)
+    acquired: bool,
+    // IRQs were already masked when locking
+    irq_masked: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> SpinLock<T> {
+        SpinLock {
+            owner: AtomicUsize::new(NO_OWNER),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /**
+     * Mask IRQs and wait until the lock is free.
+     * The previous IRQ state is restored when the guard is dropped.
+     * Panics if the lock is already held by the current core.
+     */
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let irq_masked = system_control::get_cpsr() & system_control::PSR_IRQ_MASK != 0;
+        interrupts::disable_irq();
+
+        let acquired = system_control::features_enabled(system_control::Features::MMU);
+        if acquired {
+            let core = smp::core_id();
+            if self.owner.load(Ordering::Relaxed) == core {
+                panic!("Spin lock taken again by core {}", core);
+            }
+            while self
+                .owner
+                .compare_exchange_weak(NO_OWNER, core, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                while self.owner.load(Ordering::Relaxed) != NO_OWNER {
+                    smp::wait_for_event();
+                }
+            }
+        }
+
+        SpinLockGuard {
+            lock: self,
+            acquired,
+            irq_masked,
+        }
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.acquired {
+            self.lock.owner.store(NO_OWNER, Ordering::Release);
+            smp::send_event();
+        }
+        if !self.irq_masked {
+            interrupts::enable_irq();
+        }
+    }
+}
+
+const ONCE_EMPTY: usize = 0;
+const ONCE_RUNNING: usize = 1;
+const ONCE_READY: usize = 2;
+
+pub struct Once<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Once<T> {
+        Once {
+            state: AtomicUsize::new(ONCE_EMPTY),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /**
+     * Initialize the value with `init` if this was not done yet.
+     * Concurrent callers wait until the first one has finished.
+     */
+    pub fn call_once<F: FnOnce() -> T>(&self, init: F) -> &T {
+        match self.state.compare_exchange(
+            ONCE_EMPTY,
+            ONCE_RUNNING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                unsafe { *self.value.get() = Some(init()) };
+                self.state.store(ONCE_READY, Ordering::Release);
+            }
+            Err(_) => while self.state.load(Ordering::Acquire) != ONCE_READY {},
+        }
+        self.get().unwrap()
+    }
+
+    /// Return the value if it has been initialized
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == ONCE_READY {
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+}
+
+struct MutexState {
+    locked: bool,
+    // Processes suspended while trying to lock the mutex
+    waiters: Vec<usize>,
+}
+
+pub struct Mutex<T> {
+    state: SpinLock<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub fn new(data: T) -> Mutex<T> {
+        Mutex {
+            state: SpinLock::new(MutexState {
+                locked: false,
+                waiters: Vec::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /**
+     * Lock the mutex for the process `pid` executing a system call.
+     * If the mutex is already locked, the process is suspended and None is
+     * returned. The system call must then return without touching `reg_ctx`,
+     * it is executed again when the mutex is released.
+     */
+    pub fn lock(&self, pid: usize, reg_ctx: &mut RegisterContext) -> Option<MutexGuard<T>> {
+        {
+            let mut state = self.state.lock();
+            if !state.locked {
+                state.locked = true;
+                return Some(MutexGuard { mutex: self });
+            }
+            if !state.waiters.contains(&pid) {
+                state.waiters.push(pid);
+            }
+        }
+
+        scheduler::block_and_restart(pid, ProcessState::WaitingMutex, reg_ctx);
+        None
+    }
+
+    /// Lock the mutex if it is free, used outside of system calls.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            None
+        } else {
+            state.locked = true;
+            Some(MutexGuard { mutex: self })
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = self.mutex.state.lock();
+            state.locked = false;
+            mem::replace(&mut state.waiters, Vec::new())
+        };
+
+        // All the waiters retry, the first one scheduled gets the mutex
+        for pid in waiters {
+            scheduler::wake_process(pid, ProcessState::WaitingMutex);
+        }
+    }
+}
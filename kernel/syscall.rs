@@ -1,156 +1,356 @@
-use crate::filesystem::{virtualfs, Dir};
-use crate::process::{ChildEvent, ProcessState, RegisterContext};
+use crate::filesystem::virtualfs::{self, VirtualDir};
+use crate::filesystem::{pipe, Dir, File};
+use crate::memory::application_map::{self, ApplicationMap};
+use crate::memory::user_slice::{copy_from_user, copy_to_user, UserSlice};
+use crate::memory::{cache, image_cache, PageId, PAGE_SIZE};
+use crate::process::{
+    check_elf, ChildEvent, ElfImage, ExitStatus, FileDescriptor, Process, ProcessState,
+    RegisterContext,
+};
 use crate::scheduler;
+use crate::signal;
+use crate::signal::SignalAction;
 use crate::timer;
-use core::{slice, str};
-use io::SeekFrom;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use io::{Errno, Read, SeekFrom};
+
+// Highest file descriptor that can be chosen by dup2
+const MAX_FILE_DESCRIPTOR: u32 = 1023;
+
+/**
+ * Store the result of a system call in r0.
+ * Errors are returned as negated error numbers (see `io::Errno`).
+ */
+fn set_result(reg_ctx: &mut RegisterContext, result: Result<u32, Errno>) {
+    reg_ctx.r0 = match result {
+        Ok(value) => value,
+        Err(errno) => errno.to_return_value(),
+    };
+}
 
 pub fn read(reg_ctx: &mut RegisterContext) {
-    if let Some(process) = scheduler::current_process() {
-        // Prevent write inside kernel space
-        if reg_ctx.r1 < 0x8000_0000
-            || reg_ctx.r2 >= 0x8000_0000
-            || reg_ctx.r1.overflowing_add(reg_ctx.r2).1
-        {
-            reg_ctx.r0 = 0;
-            return;
+    if let Some(pid) = scheduler::current_pid() {
+        let process = scheduler::get_process(pid).unwrap();
+        // A restarted call must not stay registered on the previous pipe
+        if let Some(waiter) = process.pipe_waiter.take() {
+            waiter.cancel(pid);
         }
 
-        let buf = unsafe { slice::from_raw_parts_mut(reg_ctx.r1 as *mut u8, reg_ctx.r2 as usize) };
+        let mut user_buf = UserSlice::new(reg_ctx.r1, reg_ctx.r2);
+        let buf = match user_buf.as_mut_slice() {
+            Ok(buf) => buf,
+            Err(errno) => {
+                set_result(reg_ctx, Err(errno));
+                return;
+            }
+        };
 
-        match process.file_descriptors.get_mut(reg_ctx.r0 as usize) {
-            Some(ref mut file) => match file.read(buf) {
-                Ok(bytes_read) => reg_ctx.r0 = bytes_read as u32,
+        let result = match process.file_descriptors.get_mut(reg_ctx.r0 as usize) {
+            Some(descr) => match descr.file.borrow_mut().read(buf) {
+                Ok(bytes_read) => Ok(bytes_read as u32),
+                Err(ref err) if err.kind == io::ErrorKind::WouldBlock => {
+                    if let Some(waiter) = &process.pipe_waiter {
+                        waiter.register(pid);
+                    }
+                    scheduler::block_and_restart(pid, ProcessState::BlockedReading, reg_ctx);
+                    return;
+                }
                 Err(err) => {
                     warn!(
                         "{}: error reading file {}: {:?}",
                         process.name, reg_ctx.r0, err
                     );
-                    reg_ctx.r0 = 0;
+                    Err(Errno::from(err))
                 }
             },
-            None => reg_ctx.r0 = 0,
-        }
+            None => Err(Errno::BadFileDescriptor),
+        };
+        set_result(reg_ctx, result);
     }
 }
 
 pub fn write(reg_ctx: &mut RegisterContext) {
-    if let Some(process) = scheduler::current_process() {
-        // Prevent read from kernel space
-        if reg_ctx.r1 < 0x8000_0000
-            || reg_ctx.r2 >= 0x8000_0000
-            || reg_ctx.r1.overflowing_add(reg_ctx.r2).1
-        {
-            reg_ctx.r0 = 0;
-            return;
+    if let Some(pid) = scheduler::current_pid() {
+        let process = scheduler::get_process(pid).unwrap();
+        // A restarted call must not stay registered on the previous pipe
+        if let Some(waiter) = process.pipe_waiter.take() {
+            waiter.cancel(pid);
         }
 
-        let buf = unsafe { slice::from_raw_parts(reg_ctx.r1 as *mut u8, reg_ctx.r2 as usize) };
+        let user_buf = UserSlice::new(reg_ctx.r1, reg_ctx.r2);
+        let buf = match user_buf.as_slice() {
+            Ok(buf) => buf,
+            Err(errno) => {
+                set_result(reg_ctx, Err(errno));
+                return;
+            }
+        };
 
-        match process.file_descriptors.get_mut(reg_ctx.r0 as usize) {
-            Some(ref mut file) => match file.write(buf) {
-                Ok(written_bytes) => reg_ctx.r0 = written_bytes as u32,
+        let result = match process.file_descriptors.get_mut(reg_ctx.r0 as usize) {
+            Some(descr) => match descr.file.borrow_mut().write(buf) {
+                Ok(written_bytes) => Ok(written_bytes as u32),
+                Err(ref err) if err.kind == io::ErrorKind::WouldBlock => {
+                    if let Some(waiter) = &process.pipe_waiter {
+                        waiter.register(pid);
+                    }
+                    scheduler::block_and_restart(pid, ProcessState::BlockedWriting, reg_ctx);
+                    return;
+                }
                 Err(err) => {
                     warn!(
                         "{}: error writing file {}: {:?}",
                         process.name, reg_ctx.r0, err
                     );
-                    reg_ctx.r0 = 0;
+                    Err(Errno::from(err))
                 }
             },
-            None => reg_ctx.r0 = 0,
-        }
+            None => Err(Errno::BadFileDescriptor),
+        };
+        set_result(reg_ctx, result);
     }
 }
 
 pub fn open(reg_ctx: &mut RegisterContext) {
-    if let Some(process) = scheduler::current_process() {
-        // Prevent read from kernel space
-        if reg_ctx.r0 < 0x8000_0000
-            || reg_ctx.r1 >= 0x8000_0000
-            || reg_ctx.r0.overflowing_add(reg_ctx.r1).1
-        {
-            reg_ctx.r0 = (-2i32) as u32;
-            return;
-        }
+    if let Some(pid) = scheduler::current_pid() {
+        let process = scheduler::get_process(pid).unwrap();
+        let path = match UserSlice::new(reg_ctx.r0, reg_ctx.r1).to_string() {
+            Ok(path) => path,
+            Err(errno) => {
+                set_result(reg_ctx, Err(errno));
+                return;
+            }
+        };
 
-        let path_bytes =
-            unsafe { slice::from_raw_parts(reg_ctx.r0 as *const u8, reg_ctx.r1 as usize) };
-        let path = str::from_utf8(path_bytes).unwrap_or("");
-        match virtualfs::get_root().open_file(path) {
+        let mut root = match virtualfs::get_root().lock(pid, reg_ctx) {
+            Some(root) => root,
+            None => return,
+        };
+        let result = match root.open_file(&path) {
             Ok(file) => {
-                let descr = process.file_descriptors.insert(file);
-                reg_ctx.r0 = descr as u32;
+                let descr = process.file_descriptors.insert(FileDescriptor::new(file));
+                Ok(descr as u32)
             }
             Err(e) => {
                 warn!("{}: cannot open file {}: {:?}", process.name, path, e);
-                // TODO: Better error desambiguation
-                reg_ctx.r0 = (-1i32) as u32;
+                Err(Errno::from(e))
             }
-        }
+        };
+        set_result(reg_ctx, result);
     }
 }
 
 pub fn close(reg_ctx: &mut RegisterContext) {
     if let Some(process) = scheduler::current_process() {
-        process.file_descriptors.remove(reg_ctx.r0 as usize);
+        let result = match process.file_descriptors.remove(reg_ctx.r0 as usize) {
+            Some(_) => Ok(0),
+            None => Err(Errno::BadFileDescriptor),
+        };
+        set_result(reg_ctx, result);
     }
 }
 
-pub fn exit(exit_code: u32) {
+pub fn reschedule(_reg_ctx: &mut RegisterContext) {
+    scheduler::plan_scheduling();
+}
+
+pub fn exit(reg_ctx: &mut RegisterContext) {
     if let Some(pid) = scheduler::current_pid() {
-        let exited_process = scheduler::remove_process(pid).unwrap();
-        scheduler::send_child_event(exited_process.parent_pid, ChildEvent { pid, exit_code });
+        scheduler::terminate_process(pid, ExitStatus::Exited(reg_ctx.r0));
     }
 }
 
 pub fn kill(reg_ctx: &mut RegisterContext) {
-    match scheduler::remove_process(reg_ctx.r0 as usize) {
-        Some(killed_process) => {
-            scheduler::send_child_event(
-                killed_process.parent_pid,
-                ChildEvent {
-                    pid: killed_process.pid,
-                    exit_code: 137,
-                },
-            );
-            reg_ctx.r0 = 0;
-        }
-        None => reg_ctx.r0 = 1,
-    }
+    let result = signal::send(reg_ctx.r0 as usize, reg_ctx.r1)
+        .map(|()| 0)
+        .map_err(Errno::from);
+    set_result(reg_ctx, result);
 }
 
 pub fn reserve_heap_pages(reg_ctx: &mut RegisterContext) {
     if let Some(current_process) = scheduler::current_process() {
-        let nb_pages = reg_ctx.r0 as isize;
-        if nb_pages >= 0 {
-            match current_process
+        let nb_pages = reg_ctx.r0 as i32;
+        let result = if nb_pages >= 0 {
+            current_process
                 .memory_map
                 .reserve_heap_pages(nb_pages as usize)
-            {
-                Ok(page_id) => reg_ctx.r0 = page_id.to_addr() as u32,
-                Err(err) => {
-                    error!("Memory allocation failure: {:?}", err);
-                    exit(102);
-                }
-            }
+                .map(|page_id| page_id.to_addr() as u32)
         } else {
-            match current_process
+            current_process
                 .memory_map
-                .free_heap_pages((-nb_pages) as usize)
-            {
-                Ok(()) => reg_ctx.r0 = 0,
+                .free_heap_pages(nb_pages.wrapping_neg() as u32 as usize)
+                .map(|()| 0)
+        };
+
+        if let Err(ref err) = result {
+            warn!("{}: heap resize failure: {:?}", current_process.name, err);
+        }
+        set_result(reg_ctx, result.map_err(Errno::from));
+    }
+}
+
+// Number of pages needed for `len` bytes given by the application
+fn page_count(len: u32) -> Result<usize, Errno> {
+    match len {
+        0 => Err(Errno::InvalidInput),
+        len => Ok((len as usize - 1) / PAGE_SIZE + 1),
+    }
+}
+
+// First page and number of pages of a memory range given by the application
+fn user_page_range(addr: u32, len: u32) -> Result<(PageId, usize), Errno> {
+    if addr as usize % PAGE_SIZE != 0 {
+        return Err(Errno::InvalidInput);
+    }
+    Ok((PageId::from(addr as usize), page_count(len)?))
+}
+
+/**
+ * Map r1 bytes of zeroed memory with the protection r2 (see `abi::PROT_*`).
+ * The region starts at the address r0, or anywhere in the mmap area if r0 is
+ * 0. Returns the address of the region.
+ */
+pub fn mmap(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let result = map_anonymous(process, reg_ctx.r0, reg_ctx.r1, reg_ctx.r2);
+        set_result(reg_ctx, result);
+    }
+}
+
+fn map_anonymous(process: &mut Process, addr: u32, len: u32, prot: u32) -> Result<u32, Errno> {
+    let flags = application_map::protection_flags(prot)?;
+    let nb_pages = page_count(len)?;
+    let first_page = match addr {
+        0 => None,
+        addr => Some(user_page_range(addr, len)?.0),
+    };
+
+    let first_page = process.memory_map.map_region(first_page, nb_pages, flags)?;
+    Ok(first_page.to_addr() as u32)
+}
+
+/**
+ * Map r2 bytes of the file r0 from the offset r1 with the protection r3.
+ * The region is a private copy of the file: changes are not written back.
+ * The part of the region after the end of the file is zeroed.
+ * Returns the address of the region.
+ */
+pub fn mmap_file(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let result = map_file(process, reg_ctx.r0, reg_ctx.r1, reg_ctx.r2, reg_ctx.r3);
+        set_result(reg_ctx, result);
+    }
+}
+
+fn map_file(
+    process: &mut Process,
+    fd: u32,
+    offset: u32,
+    len: u32,
+    prot: u32,
+) -> Result<u32, Errno> {
+    let flags = application_map::protection_flags(prot)?;
+    let nb_pages = page_count(len)?;
+    if offset as usize % PAGE_SIZE != 0 {
+        return Err(Errno::InvalidInput);
+    }
+    let file = match process.file_descriptors.get(fd as usize) {
+        Some(descr) => descr.file.clone(),
+        None => return Err(Errno::BadFileDescriptor),
+    };
+
+    let first_page = process.memory_map.map_region(None, nb_pages, flags)?;
+    let read_result = fill_region(
+        &mut **file.borrow_mut(),
+        offset as u64,
+        &mut process.memory_map,
+        first_page,
+        nb_pages,
+    );
+    if let Err(err) = read_result {
+        warn!("{}: cannot map file {}: {:?}", process.name, fd, err);
+        process.memory_map.unmap_region(first_page, nb_pages)?;
+        return Err(Errno::from(err));
+    }
+
+    if flags.execute {
+        cache::invalidate_instr_cache();
+        cache::invalidate_branch_predictor();
+    }
+    Ok(first_page.to_addr() as u32)
+}
+
+/**
+ * Copy the content of the file from `offset` into the pages of a region,
+ * until the end of the region or of the file.
+ * The offset of the open file is shared with other descriptors, it is
+ * restored afterwards.
+ */
+fn fill_region(
+    file: &mut File,
+    offset: u64,
+    memory_map: &mut ApplicationMap,
+    first_page: PageId,
+    nb_pages: usize,
+) -> io::Result<()> {
+    let saved_offset = file.seek(SeekFrom::Current(0))?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut result = Ok(());
+    'pages: for page in first_page.0..first_page.0 + nb_pages {
+        let content = memory_map.region_page_content(PageId(page)).unwrap();
+        let mut filled = 0;
+        while filled < PAGE_SIZE {
+            match file.read(&mut content[filled..]) {
+                Ok(0) => break 'pages,
+                Ok(bytes_read) => filled += bytes_read,
                 Err(err) => {
-                    error!("Memory deallocation failure: {:?}", err);
-                    exit(102);
+                    result = Err(err);
+                    break 'pages;
                 }
             }
         }
     }
+
+    file.seek(SeekFrom::Start(saved_offset))?;
+    result
+}
+
+/// Unmap the pages of the mmap area between r0 and r0 + r1.
+pub fn munmap(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let result = user_page_range(reg_ctx.r0, reg_ctx.r1).and_then(|(first_page, nb_pages)| {
+            process
+                .memory_map
+                .unmap_region(first_page, nb_pages)
+                .map(|()| 0)
+                .map_err(Errno::from)
+        });
+        set_result(reg_ctx, result);
+    }
+}
+
+/// Change the protection of the pages of the mmap area between r0 and r0 + r1.
+pub fn mprotect(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let prot = reg_ctx.r2;
+        let result = user_page_range(reg_ctx.r0, reg_ctx.r1).and_then(|(first_page, nb_pages)| {
+            let flags = application_map::protection_flags(prot)?;
+            process
+                .memory_map
+                .protect_region(first_page, nb_pages, flags)
+                .map(|()| 0)
+                .map_err(Errno::from)
+        });
+        set_result(reg_ctx, result);
+    }
 }
 
 pub fn sleep(reg_ctx: &mut RegisterContext) {
-    let micro_secs = reg_ctx.r0.saturating_mul(1000);
+    let micro_secs = reg_ctx.r0 as u64 * 1000;
     if micro_secs == 0 {
         return;
     }
@@ -163,63 +363,528 @@ pub fn sleep(reg_ctx: &mut RegisterContext) {
     }
 }
 
+// Give the pid and the exit status of the child in r0, r1 and r2
+fn return_child_event(child_event: ChildEvent, reg_ctx: &mut RegisterContext) {
+    let (status_kind, status_value) = child_event.status.to_regs();
+    reg_ctx.r0 = child_event.pid as u32;
+    reg_ctx.r1 = status_kind;
+    reg_ctx.r2 = status_value;
+}
+
 pub fn wait_children(reg_ctx: &mut RegisterContext) {
     if let Some(pid) = scheduler::current_pid() {
-        let process = scheduler::get_process(pid).unwrap();
-        match process.child_events.pop() {
-            Some(child_event) => {
-                reg_ctx.r0 = child_event.pid as u32;
-                reg_ctx.r1 = child_event.exit_code;
+        match scheduler::reap_child(pid, None) {
+            Some(child_event) => return_child_event(child_event, reg_ctx),
+            None => scheduler::block_and_restart(pid, ProcessState::WaitingChildren, reg_ctx),
+        }
+    }
+}
+
+// Value of the pid argument of waitpid meaning any child
+const WAIT_ANY_CHILD: u32 = !0;
+// Flag of waitpid returning immediately if no child has ended yet
+const WAIT_NO_HANG: u32 = 1;
+
+/**
+ * Wait for the end of the child given in r0, or of any child if r0 is -1.
+ * Returns 0 in r0 if the flag WAIT_NO_HANG is set in r1 and the child is
+ * still running, or fails with NoChild if there is no such child.
+ */
+pub fn waitpid(reg_ctx: &mut RegisterContext) {
+    if let Some(pid) = scheduler::current_pid() {
+        let target = match reg_ctx.r0 {
+            WAIT_ANY_CHILD => None,
+            child_pid => Some(child_pid as usize),
+        };
+
+        if let Some(child_event) = scheduler::reap_child(pid, target) {
+            return_child_event(child_event, reg_ctx);
+            return;
+        }
+
+        // Children stay in this list until they are reaped
+        let children_pid = &scheduler::get_process(pid).unwrap().children_pid;
+        let child_running = match target {
+            None => !children_pid.is_empty(),
+            Some(target) => children_pid.contains(&target),
+        };
+
+        if !child_running {
+            set_result(reg_ctx, Err(Errno::NoChild));
+        } else if reg_ctx.r1 & WAIT_NO_HANG != 0 {
+            reg_ctx.r0 = 0;
+        } else {
+            scheduler::block_and_restart(pid, ProcessState::WaitingChildren, reg_ctx);
+        }
+    }
+}
+
+fn is_child_of(child_pid: usize, parent_pid: usize) -> bool {
+    match scheduler::get_process(child_pid) {
+        Some(child) => child.parent_pid == parent_pid,
+        None => false,
+    }
+}
+
+/**
+ * Find the process targeted by getpriority, setpriority or the page limit
+ * system calls. The pid 0 means the calling process, this is not ambiguous as
+ * init can only target itself.
+ * Processes can only target themselves and their children.
+ */
+fn target_process(pid: usize, target: u32) -> Result<usize, Errno> {
+    let target = match target {
+        0 => pid,
+        target => target as usize,
+    };
+    if target == pid || is_child_of(target, pid) {
+        Ok(target)
+    } else if scheduler::get_process(target).is_some() {
+        Err(Errno::PermissionDenied)
+    } else {
+        Err(Errno::NoSuchProcess)
+    }
+}
+
+// Only init can lower the nice value of a process, others can only raise it
+fn check_nice(pid: usize, target: usize, nice: i32) -> Result<(), Errno> {
+    let current_nice = scheduler::get_process(target).unwrap().nice;
+    if pid != scheduler::INIT_PID && nice.max(scheduler::MIN_NICE) < current_nice {
+        Err(Errno::PermissionDenied)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn nice(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let nice = process.nice.saturating_add(reg_ctx.r0 as i32);
+        let result = check_nice(process.pid, process.pid, nice).map(|()| {
+            scheduler::set_nice(process.pid, nice);
+            (process.nice + abi::NICE_OFFSET) as u32
+        });
+        set_result(reg_ctx, result);
+    }
+}
+
+pub fn getpriority(reg_ctx: &mut RegisterContext) {
+    if let Some(pid) = scheduler::current_pid() {
+        let result = target_process(pid, reg_ctx.r0).map(|target| {
+            let nice = scheduler::get_process(target).unwrap().nice;
+            (nice + abi::NICE_OFFSET) as u32
+        });
+        set_result(reg_ctx, result);
+    }
+}
+
+pub fn setpriority(reg_ctx: &mut RegisterContext) {
+    if let Some(pid) = scheduler::current_pid() {
+        let nice = reg_ctx.r1 as i32;
+        let result = target_process(pid, reg_ctx.r0).and_then(|target| {
+            check_nice(pid, target, nice)?;
+            scheduler::set_nice(target, nice);
+            Ok(0)
+        });
+        set_result(reg_ctx, result);
+    }
+}
+
+pub fn get_page_limit(reg_ctx: &mut RegisterContext) {
+    if let Some(pid) = scheduler::current_pid() {
+        let result = target_process(pid, reg_ctx.r0).map(|target| {
+            let memory_map = &scheduler::get_process(target).unwrap().memory_map;
+            memory_map
+                .page_limit()
+                .map_or(abi::NO_PAGE_LIMIT, |limit| limit as u32)
+        });
+        set_result(reg_ctx, result);
+    }
+}
+
+/**
+ * Limit the number of pages mapped by the calling process or one of its
+ * children. The limit cannot be higher than the one of the calling process,
+ * so that a process cannot escape the limit given by its parent.
+ */
+pub fn set_page_limit(reg_ctx: &mut RegisterContext) {
+    if let Some(pid) = scheduler::current_pid() {
+        let page_limit = match reg_ctx.r1 {
+            abi::NO_PAGE_LIMIT => None,
+            limit => Some(limit as usize),
+        };
+        let own_limit = scheduler::get_process(pid).unwrap().memory_map.page_limit();
+        let result = target_process(pid, reg_ctx.r0).and_then(|target| {
+            match (own_limit, page_limit) {
+                (Some(own_limit), Some(limit)) if limit > own_limit => {
+                    return Err(Errno::PermissionDenied)
+                }
+                (Some(_), None) => return Err(Errno::PermissionDenied),
+                _ => (),
             }
-            None => {
-                process.state = ProcessState::WaitingChildren;
-                scheduler::suspend_process(pid);
+            let memory_map = &mut scheduler::get_process(target).unwrap().memory_map;
+            memory_map.set_page_limit(page_limit);
+            Ok(0)
+        });
+        set_result(reg_ctx, result);
+    }
+}
+
+/**
+ * Write the processor time used by the current process, or by its ended
+ * children, as an `abi::ResourceUsage` at the address in r1.
+ */
+pub fn getrusage(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let cpu_times = match reg_ctx.r0 {
+            abi::RUSAGE_SELF => process.cpu_times,
+            abi::RUSAGE_CHILDREN => process.children_cpu_times,
+            _ => {
+                set_result(reg_ctx, Err(Errno::InvalidInput));
+                return;
             }
-        }
+        };
+        let result = copy_to_user(reg_ctx.r1 as usize, cpu_times.to_usage()).map(|()| 0);
+        set_result(reg_ctx, result);
     }
 }
 
+/**
+ * Fill the array of `abi::ProcessInfo` at r0 with at most r1 entries.
+ * Returns the total number of processes, which may be greater than r1.
+ */
+pub fn list_processes(reg_ctx: &mut RegisterContext) {
+    let infos = scheduler::process_infos();
+    let max_infos = reg_ctx.r1 as usize;
+    let result = infos
+        .iter()
+        .take(max_infos)
+        .enumerate()
+        .try_for_each(|(i, info)| {
+            let addr = (reg_ctx.r0 as usize)
+                .checked_add(i * size_of::<abi::ProcessInfo>())
+                .ok_or(Errno::BadAddress)?;
+            copy_to_user(addr, *info)
+        })
+        .map(|()| infos.len() as u32);
+    set_result(reg_ctx, result);
+}
+
 pub fn seek(reg_ctx: &mut RegisterContext) {
     if let Some(process) = scheduler::current_process() {
-        let seek_from;
-        match reg_ctx.r1 {
-            0 => seek_from = SeekFrom::Start((reg_ctx.r2 as u64) << 32 | reg_ctx.r3 as u64),
-            1 => seek_from = SeekFrom::End(((reg_ctx.r2 as u64) << 32 | reg_ctx.r3 as u64) as i64),
-            2 => {
-                seek_from =
-                    SeekFrom::Current(((reg_ctx.r2 as u64) << 32 | reg_ctx.r3 as u64) as i64)
-            }
+        let offset = (reg_ctx.r2 as u64) << 32 | reg_ctx.r3 as u64;
+        let seek_from = match reg_ctx.r1 {
+            0 => SeekFrom::Start(offset),
+            1 => SeekFrom::End(offset as i64),
+            2 => SeekFrom::Current(offset as i64),
             _ => {
-                error!("{}: Invalid seek operation", process.name);
-                exit(105);
+                set_result(reg_ctx, Err(Errno::InvalidInput));
                 return;
             }
-        }
+        };
 
-        match process.file_descriptors.get_mut(reg_ctx.r0 as usize) {
-            Some(ref mut file) => match file.seek(seek_from) {
+        let result = match process.file_descriptors.get_mut(reg_ctx.r0 as usize) {
+            Some(descr) => match descr.file.borrow_mut().seek(seek_from) {
                 Ok(offset) => {
-                    reg_ctx.r0 = (offset >> 32) as u32;
-                    reg_ctx.r1 = offset as u32;
+                    reg_ctx.r1 = (offset >> 32) as u32;
+                    reg_ctx.r2 = offset as u32;
+                    Ok(0)
                 }
                 Err(err) => {
                     warn!(
                         "{}: error seeking file {}: {:?}",
                         process.name, reg_ctx.r0, err
                     );
-                    reg_ctx.r0 = 0;
-                    reg_ctx.r1 = 0;
+                    Err(Errno::from(err))
                 }
             },
+            None => Err(Errno::BadFileDescriptor),
+        };
+        set_result(reg_ctx, result);
+    }
+}
+
+/// Read the whole content of the file at the given path in the virtual
+/// filesystem.
+fn read_file(root: &mut VirtualDir, path: &str) -> io::Result<Vec<u8>> {
+    let mut file = root.open_file(path)?;
+    let mut content = Vec::new();
+    let mut buf = [0; 512];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(content),
+            bytes_read => content.extend_from_slice(&buf[0..bytes_read]),
+        }
+    }
+}
+
+/// Return the image of the executable at the given path, from the image cache
+/// if possible.
+fn load_image(root: &mut VirtualDir, path: &str) -> io::Result<ElfImage> {
+    let identity = root.file_identity(path).ok();
+    image_cache::get_image(identity, || read_file(root, path))
+}
+
+/**
+ * Copy an array of strings from application memory.
+ * The array is given as `count` consecutive (address, length) pairs
+ * starting at `addr`.
+ */
+fn read_user_str_array(addr: u32, count: u32) -> Result<Vec<String>, Errno> {
+    (0..count as usize)
+        .map(|i| {
+            let entry_addr = (addr as usize)
+                .checked_add(8 * i)
+                .ok_or(Errno::BadAddress)?;
+            let [str_addr, str_len] = copy_from_user::<[u32; 2]>(entry_addr)?;
+            UserSlice::new(str_addr, str_len).to_string()
+        })
+        .collect()
+}
+
+pub fn spawn(reg_ctx: &mut RegisterContext) {
+    if let Some(pid) = scheduler::current_pid() {
+        let mut root = match virtualfs::get_root().lock(pid, reg_ctx) {
+            Some(root) => root,
+            None => return,
+        };
+        let result = spawn_child(pid, &mut root, reg_ctx);
+        set_result(reg_ctx, result);
+    }
+}
+
+fn spawn_child(pid: usize, root: &mut VirtualDir, reg_ctx: &RegisterContext) -> Result<u32, Errno> {
+    // The path and arguments must be copied in kernel memory because the
+    // application map of the parent is deactivated while loading the child.
+    let path = UserSlice::new(reg_ctx.r0, reg_ctx.r1).to_string()?;
+    let args = read_user_str_array(reg_ctx.r2, reg_ctx.r3)?;
+    let name = path.rsplit('/').next().unwrap_or("").to_owned();
+
+    let parent = scheduler::get_process(pid).unwrap();
+    let image = load_image(root, &path).map_err(|e| {
+        warn!("{}: cannot read executable {}: {:?}", parent.name, path, e);
+        Errno::from(e)
+    })?;
+
+    if let Err(e) = check_elf(image.data()) {
+        warn!("{}: cannot spawn {}: {:?}", parent.name, path, e);
+        return Err(Errno::from(e));
+    }
+
+    let child = Process::new(name, image, &args, parent.environment.clone());
+
+    // Loading the ELF file has activated the child memory map
+    parent.memory_map.activate();
+
+    let mut child = child.map_err(|e| {
+        warn!("{}: cannot spawn {}: {:?}", parent.name, path, e);
+        Errno::from(e)
+    })?;
+    child.parent_pid = pid;
+    child.nice = parent.nice;
+    child
+        .memory_map
+        .set_page_limit(parent.memory_map.page_limit());
+    child.inherit_standard_streams(parent);
+    let child_pid = scheduler::add_process(Box::new(child));
+    parent.children_pid.push(child_pid);
+    Ok(child_pid as u32)
+}
+
+pub fn fork(reg_ctx: &mut RegisterContext) {
+    if let Some(pid) = scheduler::current_pid() {
+        let parent = scheduler::get_process(pid).unwrap();
+        parent.save_context(reg_ctx);
+
+        let result = parent.fork().map_err(Errno::from).map(|mut child| {
+            child.regs.r0 = 0;
+            let child_pid = scheduler::add_process(Box::new(child));
+            parent.children_pid.push(child_pid);
+            child_pid as u32
+        });
+        set_result(reg_ctx, result);
+    }
+}
+
+pub fn exec(reg_ctx: &mut RegisterContext) {
+    if let Some(pid) = scheduler::current_pid() {
+        let mut root = match virtualfs::get_root().lock(pid, reg_ctx) {
+            Some(root) => root,
+            None => return,
+        };
+        let loaded = load_executable(pid, &mut root, reg_ctx);
+        drop(root);
+
+        let (path, args, image) = match loaded {
+            Ok(loaded) => loaded,
+            Err(errno) => {
+                set_result(reg_ctx, Err(errno));
+                return;
+            }
+        };
+        let name = path.rsplit('/').next().unwrap_or("").to_owned();
+
+        let process = scheduler::get_process(pid).unwrap();
+        match process.exec(name, image, &args) {
+            Ok(()) => *reg_ctx = process.regs.clone(),
+            Err(e) => {
+                // The process keeps running its previous program
+                warn!("{}: cannot load {}: {:?}", process.name, path, e);
+                set_result(reg_ctx, Err(Errno::from(e)));
+            }
+        }
+    }
+}
+
+// Read and check the executable and arguments given to exec
+fn load_executable(
+    pid: usize,
+    root: &mut VirtualDir,
+    reg_ctx: &RegisterContext,
+) -> Result<(String, Vec<String>, ElfImage), Errno> {
+    let path = UserSlice::new(reg_ctx.r0, reg_ctx.r1).to_string()?;
+    let args = read_user_str_array(reg_ctx.r2, reg_ctx.r3)?;
+
+    let process = scheduler::get_process(pid).unwrap();
+    let image = load_image(root, &path).map_err(|e| {
+        warn!("{}: cannot read executable {}: {:?}", process.name, path, e);
+        Errno::from(e)
+    })?;
+
+    // Rejected before the process is changed, see Process::exec
+    if let Err(e) = check_elf(image.data()) {
+        warn!("{}: cannot exec {}: {:?}", process.name, path, e);
+        return Err(Errno::from(e));
+    }
+
+    Ok((path, args, image))
+}
+
+pub fn set_close_on_exec(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let result = match process.file_descriptors.get_mut(reg_ctx.r0 as usize) {
+            Some(descr) => {
+                descr.close_on_exec = reg_ctx.r1 != 0;
+                Ok(0)
+            }
+            None => Err(Errno::BadFileDescriptor),
+        };
+        set_result(reg_ctx, result);
+    }
+}
+
+pub fn pipe(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let (reader, writer) = pipe::new();
+        let read_descr = process
+            .file_descriptors
+            .insert(FileDescriptor::new(Box::new(reader)));
+        let write_descr = process
+            .file_descriptors
+            .insert(FileDescriptor::new(Box::new(writer)));
+
+        reg_ctx.r0 = read_descr as u32;
+        reg_ctx.r1 = write_descr as u32;
+    }
+}
+
+pub fn dup(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let file = match process.file_descriptors.get(reg_ctx.r0 as usize) {
+            Some(descr) => descr.file.clone(),
             None => {
-                reg_ctx.r0 = 0;
-                reg_ctx.r1 = 0;
+                set_result(reg_ctx, Err(Errno::BadFileDescriptor));
+                return;
             }
+        };
+
+        let new_descr = process.file_descriptors.insert(FileDescriptor {
+            file,
+            close_on_exec: false,
+        });
+        reg_ctx.r0 = new_descr as u32;
+    }
+}
+
+pub fn dup2(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let file = match process.file_descriptors.get(reg_ctx.r0 as usize) {
+            Some(descr) => descr.file.clone(),
+            None => {
+                set_result(reg_ctx, Err(Errno::BadFileDescriptor));
+                return;
+            }
+        };
+
+        if reg_ctx.r1 > MAX_FILE_DESCRIPTOR {
+            set_result(reg_ctx, Err(Errno::BadFileDescriptor));
+            return;
+        }
+
+        if reg_ctx.r0 != reg_ctx.r1 {
+            // Any file previously open with this descriptor is closed
+            process.file_descriptors.insert_at(
+                reg_ctx.r1 as usize,
+                FileDescriptor {
+                    file,
+                    close_on_exec: false,
+                },
+            );
         }
+        reg_ctx.r0 = reg_ctx.r1;
+    }
+}
+
+pub fn sigaction(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let action = match reg_ctx.r1 {
+            0 => SignalAction::Default,
+            1 => SignalAction::Ignore,
+            handler => SignalAction::Handler {
+                handler,
+                restorer: reg_ctx.r2,
+                mask: reg_ctx.r3,
+            },
+        };
+
+        let result = match process.signals.set_action(reg_ctx.r0, action) {
+            Ok(_) => Ok(0),
+            Err(err) => {
+                warn!(
+                    "{}: cannot set action of signal {}: {:?}",
+                    process.name, reg_ctx.r0, err
+                );
+                Err(Errno::from(err))
+            }
+        };
+        set_result(reg_ctx, result);
+    }
+}
+
+pub fn sigprocmask(reg_ctx: &mut RegisterContext) {
+    if let Some(process) = scheduler::current_process() {
+        let old_mask = process.signals.blocked;
+        let new_mask = match reg_ctx.r0 {
+            0 => old_mask | reg_ctx.r1,
+            1 => old_mask & !reg_ctx.r1,
+            2 => reg_ctx.r1,
+            _ => {
+                set_result(reg_ctx, Err(Errno::InvalidInput));
+                return;
+            }
+        };
+        process.signals.set_blocked(new_mask);
+        // The mask can take any value, it cannot share r0 with the errors
+        reg_ctx.r1 = old_mask;
+        set_result(reg_ctx, Ok(0));
     }
 }
 
-/*pub fn spawn(reg_ctx: &mut RegisterContext)
-{
-    unimplemented!()
-}*/
+pub fn sigreturn(reg_ctx: &mut RegisterContext) {
+    if let Some(pid) = scheduler::current_pid() {
+        if let Err(err) = signal::restore_frame(pid, reg_ctx) {
+            let process = scheduler::get_process(pid).unwrap();
+            error!("{}: invalid signal frame: {:?}", process.name, err);
+            scheduler::terminate_process(pid, ExitStatus::Signaled(signal::SIGSEGV));
+        }
+    }
+}
@@ -11,6 +11,11 @@ pub enum ProcessorMode {
     System = 0b11111,
 }
 
+/// Bit of the program status register set when executing Thumb code
+pub const PSR_THUMB: u32 = 1 << 5;
+/// Bit of the program status register masking IRQs
+pub const PSR_IRQ_MASK: u32 = 1 << 7;
+
 pub fn get_cpsr() -> u32 {
     unsafe {
         let cpsr;
@@ -60,6 +65,11 @@ pub unsafe fn disable_features(features: Features) {
     SCTLR::reset_bits(features.bits());
 }
 
+/// Check if all the given features are enabled on the current core
+pub fn features_enabled(features: Features) -> bool {
+    unsafe { SCTLR::read() & features.bits() == features.bits() }
+}
+
 pub fn enable_fpu() {
     unsafe {
         CPACR::write(0b1111 << 20);
@@ -1,77 +1,205 @@
+/*!
+ * Kernel timers based on the system timer.
+ *
+ * A timer calls a function with a data word once its deadline, given in
+ * microseconds of system timer, has passed. Deadlines are 64-bit values so
+ * they do not wrap. The compare register of the system timer only matches the
+ * low 32 bits of the counter, so the callback checks the full deadlines and
+ * programs the register again for timers far in the future.
+ */
+
 use crate::process::ProcessState;
 use crate::scheduler;
-use alloc::collections::binary_heap::BinaryHeap;
+use crate::sync::{Once, SpinLock};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use drivers::core_timer;
 use drivers::system_timer;
 use drivers::system_timer::SystemTimer;
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-struct Trigger {
-    time: u32,
-    pid: usize,
+/**
+ * Identifier of a pending timer returned by `add_timer`.
+ * Handles are never reused, cancelling a timer that already fired has no
+ * effect.
+ */
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub struct TimerHandle {
+    deadline: u64,
+    id: u64,
+}
+
+struct Timer {
+    callback: fn(usize),
+    data: usize,
 }
 
 struct TimerHandler {
-    next_wakeups: BinaryHeap<Trigger>,
-    wakeup_trigger: Option<Trigger>,
+    // Pending timers ordered by deadline
+    timers: BTreeMap<TimerHandle, Timer>,
+    next_id: u64,
+    // The IRQ callback is registered
+    armed: bool,
 }
 
-static mut TIMER_HANDLER: Option<TimerHandler> = None;
+static TIMER_HANDLER: Once<SpinLock<TimerHandler>> = Once::new();
+
+// Minimum delay before the trigger time, so that it is not already passed
+// when the compare register is written
+const MIN_TRIGGER_DELAY: u64 = 10;
+// Maximum delay before the trigger time, the compare register only matches
+// the low 32 bits of the system timer
+const MAX_TRIGGER_DELAY: u64 = 1 << 31;
+
+// Duration of the measure of the core timer frequency, in microseconds
+const CALIBRATION_TIME: u64 = 10_000;
+// Number of core timer ticks by millisecond
+static CORE_TICKS_BY_MSEC: Once<u64> = Once::new();
+
+// Measure the frequency of the core timers against the system timer, which
+// counts microseconds. The frequency of the core timers depends on the clock.
+fn calibrate_core_timer() -> u64 {
+    let start_time = system_timer::get_time();
+    let start_ticks = core_timer::get_time(core_timer::Virtual);
+    while system_timer::get_time() - start_time < CALIBRATION_TIME {}
+    let ticks = core_timer::get_time(core_timer::Virtual) - start_ticks;
+    ticks * 1000 / CALIBRATION_TIME
+}
+
+/// Convert a duration measured with the core timers to microseconds.
+pub fn core_ticks_to_micros(ticks: u64) -> u64 {
+    let ticks_by_msec = *CORE_TICKS_BY_MSEC.get().expect("Core timer not calibrated");
+    ticks / ticks_by_msec * 1000 + ticks % ticks_by_msec * 1000 / ticks_by_msec
+}
 
 pub fn init() {
-    unsafe {
-        TIMER_HANDLER = Some(TimerHandler {
-            next_wakeups: BinaryHeap::new(),
-            wakeup_trigger: None,
-        });
+    CORE_TICKS_BY_MSEC.call_once(calibrate_core_timer);
+    TIMER_HANDLER.call_once(|| {
+        SpinLock::new(TimerHandler {
+            timers: BTreeMap::new(),
+            next_id: 0,
+            armed: false,
+        })
+    });
+}
+
+impl TimerHandler {
+    // Program the system timer for the earliest deadline
+    fn program_next(&mut self) {
+        match self.timers.keys().next() {
+            Some(handle) => {
+                let now = system_timer::get_time();
+                let trigger_time = handle
+                    .deadline
+                    .max(now + MIN_TRIGGER_DELAY)
+                    .min(now + MAX_TRIGGER_DELAY);
+                system_timer::set_trigger_time(SystemTimer::Timer1, trigger_time as u32);
+                if !self.armed {
+                    system_timer::register_callback(SystemTimer::Timer1, timer_callback);
+                    self.armed = true;
+                }
+            }
+            None => {
+                if self.armed {
+                    system_timer::unregister_callback(SystemTimer::Timer1);
+                    self.armed = false;
+                }
+            }
+        }
+    }
+
+    // Remove the timers whose deadline has passed
+    fn take_expired(&mut self, now: u64) -> Vec<Timer> {
+        let mut expired = Vec::new();
+        while let Some(&handle) = self.timers.keys().next() {
+            if handle.deadline > now {
+                break;
+            }
+            expired.push(self.timers.remove(&handle).unwrap());
+        }
+        expired
     }
 }
 
 fn timer_callback() {
-    let timer_handler = unsafe { TIMER_HANDLER.as_mut().unwrap() };
+    system_timer::clear_irq(SystemTimer::Timer1);
+
+    let expired = TIMER_HANDLER
+        .get()
+        .unwrap()
+        .lock()
+        .take_expired(system_timer::get_time());
+
+    // The lock is released so that callbacks can add timers
+    for timer in expired {
+        (timer.callback)(timer.data);
+    }
+
+    TIMER_HANDLER.get().unwrap().lock().program_next();
+}
 
-    let trigger = timer_handler.wakeup_trigger.unwrap();
-    if let Some(process) = scheduler::get_process(trigger.pid) {
+/**
+ * Call `callback(data)` from the timer interrupt once the system timer
+ * reaches `deadline` (in microseconds, see `system_timer::get_time`).
+ * Callbacks run with the big kernel lock held and must be short.
+ */
+pub fn add_timer(deadline: u64, callback: fn(usize), data: usize) -> TimerHandle {
+    let mut timer_handler = TIMER_HANDLER.get().unwrap().lock();
+
+    let handle = TimerHandle {
+        deadline,
+        id: timer_handler.next_id,
+    };
+    timer_handler.next_id += 1;
+    timer_handler
+        .timers
+        .insert(handle, Timer { callback, data });
+
+    if timer_handler.timers.keys().next() == Some(&handle) {
+        timer_handler.program_next();
+    }
+    handle
+}
+
+/// Call `callback(data)` after `micro_secs` microseconds, see `add_timer`.
+pub fn add_timer_after(micro_secs: u64, callback: fn(usize), data: usize) -> TimerHandle {
+    add_timer(system_timer::get_time() + micro_secs, callback, data)
+}
+
+/**
+ * Cancel a pending timer.
+ * Returns false if the timer has already fired or was already cancelled.
+ */
+pub fn cancel_timer(handle: TimerHandle) -> bool {
+    let mut timer_handler = TIMER_HANDLER.get().unwrap().lock();
+    let removed = timer_handler.timers.remove(&handle).is_some();
+    if removed {
+        timer_handler.program_next();
+    }
+    removed
+}
+
+// Timer callback ending the sleep of the process `pid`
+fn wake_sleeping_process(pid: usize) {
+    if let Some(process) = scheduler::get_process(pid) {
+        process.wakeup_timer = None;
         if process.state == ProcessState::WaitingTimer {
             process.state = ProcessState::Runnable;
-            scheduler::resume_process(trigger.pid)
+            scheduler::resume_process(pid)
         } else {
             warn!("Wakeup signal on already runnable process")
         }
     }
-
-    match timer_handler.next_wakeups.pop() {
-        Some(trig) => {
-            system_timer::set_trigger_time(SystemTimer::Timer1, trig.time);
-            timer_handler.wakeup_trigger = Some(trig);
-        }
-        None => {
-            system_timer::unregister_callback(SystemTimer::Timer1);
-            timer_handler.wakeup_trigger = None;
-        }
-    }
-
-    system_timer::clear_irq(SystemTimer::Timer1);
 }
 
-pub fn add_wakeup_event(pid: usize, micro_secs: u32) {
-    let timer_handler = unsafe { TIMER_HANDLER.as_mut().unwrap() };
-
-    let time = system_timer::get_time_low().wrapping_add(micro_secs);
-    let trig = Trigger { pid, time };
-    match timer_handler.wakeup_trigger {
-        Some(other_trig) => {
-            if other_trig.time <= time {
-                timer_handler.next_wakeups.push(trig);
-            } else {
-                timer_handler.wakeup_trigger = Some(trig);
-                system_timer::set_trigger_time(SystemTimer::Timer1, time);
-                timer_handler.next_wakeups.push(other_trig.clone());
-            }
-        }
-        None => {
-            timer_handler.wakeup_trigger = Some(trig);
-            system_timer::set_trigger_time(SystemTimer::Timer1, time);
-            system_timer::register_callback(SystemTimer::Timer1, timer_callback);
+/**
+ * Wake the process `pid` after `micro_secs` microseconds.
+ * The timer is cancelled if the process is removed before.
+ */
+pub fn add_wakeup_event(pid: usize, micro_secs: u64) {
+    let handle = add_timer_after(micro_secs, wake_sleeping_process, pid);
+    if let Some(process) = scheduler::get_process(pid) {
+        if let Some(old_handle) = process.wakeup_timer.replace(handle) {
+            cancel_timer(old_handle);
         }
     }
 }
@@ -8,9 +8,6 @@ extern crate rustberry_std as std;
 pub extern "C" fn main() {
     loop {
         let child_ev = std::syscall::wait_children();
-        print!(
-            "Child {} ended with exit code {}",
-            child_ev.pid, child_ev.exit_code
-        );
+        print!("Child {} ended: {:?}", child_ev.pid, child_ev.status);
     }
 }
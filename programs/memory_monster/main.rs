@@ -1,13 +1,51 @@
+/*!
+ * Exhaust the physical memory from several processes at once.
+ * The kernel must kill the monsters one by one and keep running: their parent
+ * then reports how each of them ended and can still fork.
+ */
+
 #![no_std]
 #![feature(alloc)]
 #[macro_use]
 extern crate alloc;
+#[macro_use]
 extern crate rustberry_std as std;
 
-#[no_mangle]
-pub extern "C" fn main() {
+const NB_MONSTERS: usize = 3;
+
+fn eat_memory() -> ! {
     let mut a = vec![];
     loop {
         a.push(42);
     }
 }
+
+#[no_mangle]
+pub extern "C" fn main() {
+    let mut nb_monsters = 0;
+    for _ in 0..NB_MONSTERS {
+        match std::syscall::fork() {
+            Ok(0) => eat_memory(),
+            Ok(_) => nb_monsters += 1,
+            Err(err) => println!("Cannot fork a monster: {:?}", err),
+        }
+    }
+
+    for _ in 0..nb_monsters {
+        let child_ev = std::syscall::wait_children();
+        println!("Monster {} ended: {:?}", child_ev.pid, child_ev.status);
+    }
+
+    // Forking again checks that the kernel recovered the memory
+    match std::syscall::fork() {
+        Ok(0) => std::syscall::exit(0),
+        Ok(_) => {
+            let child_ev = std::syscall::wait_children();
+            println!(
+                "Kernel survived the monsters, new child ended: {:?}",
+                child_ev.status
+            );
+        }
+        Err(err) => println!("Cannot fork after the monsters: {:?}", err),
+    }
+}
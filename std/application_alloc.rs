@@ -10,11 +10,16 @@ unsafe impl HeapPageAlloc for HeapAllocator {
     }
 
     unsafe fn reserve_heap_pages(&mut self, nb: usize) -> usize {
-        syscall::reserve_heap_pages(nb as isize)
+        match syscall::reserve_heap_pages(nb as isize) {
+            Ok(first_page) => first_page,
+            Err(err) => panic!("cannot grow the heap: {}", err.error),
+        }
     }
 
     unsafe fn free_heap_pages(&mut self, nb: usize) {
-        syscall::reserve_heap_pages(-(nb as isize));
+        if let Err(err) = syscall::reserve_heap_pages(-(nb as isize)) {
+            panic!("cannot shrink the heap: {}", err.error);
+        }
     }
 }
 
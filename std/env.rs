@@ -0,0 +1,98 @@
+/*!
+ * Access to the arguments and the environment of the process.
+ *
+ * Both are laid out by the kernel on the top of the initial stack and are
+ * never moved or freed, so they can be handed out as `&'static str`.
+ */
+
+use core::{slice, str};
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct RawStr {
+    addr: *const u8,
+    len: usize,
+}
+
+impl RawStr {
+    fn as_str(&self) -> &'static str {
+        // The kernel only passes valid UTF-8 strings
+        unsafe { str::from_utf8_unchecked(slice::from_raw_parts(self.addr, self.len)) }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct RawVar {
+    key: RawStr,
+    value: RawStr,
+}
+
+static mut ARGS: &'static [RawStr] = &[];
+static mut VARS: &'static [RawVar] = &[];
+
+/// Register the arguments and environment given by the kernel at startup.
+pub(crate) unsafe fn init(argc: usize, argv: *const RawStr, envc: usize, envp: *const RawVar) {
+    ARGS = slice::from_raw_parts(argv, argc);
+    VARS = slice::from_raw_parts(envp, envc);
+}
+
+/// Iterator over the arguments of the process.
+pub struct Args {
+    inner: slice::Iter<'static, RawStr>,
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        self.inner.next().map(RawStr::as_str)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Args {}
+
+/// Iterator over the (key, value) pairs of the environment of the process.
+pub struct Vars {
+    inner: slice::Iter<'static, RawVar>,
+}
+
+impl Iterator for Vars {
+    type Item = (&'static str, &'static str);
+
+    fn next(&mut self) -> Option<(&'static str, &'static str)> {
+        self.inner
+            .next()
+            .map(|var| (var.key.as_str(), var.value.as_str()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Vars {}
+
+/// Return the arguments the process was started with.
+/// The first argument is usually the name of the program.
+pub fn args() -> Args {
+    Args {
+        inner: unsafe { ARGS.iter() },
+    }
+}
+
+/// Return the environment variables of the process.
+pub fn vars() -> Vars {
+    Vars {
+        inner: unsafe { VARS.iter() },
+    }
+}
+
+/// Return the value of the environment variable `key` if it is defined.
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find(|(k, _)| *k == key).map(|(_, value)| value)
+}
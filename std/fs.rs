@@ -1,7 +1,7 @@
 use crate::syscall;
 //use crate::syscall::OpenFlags;
-use io;
-use io::{Read, Seek, SeekFrom, Write};
+use crate::io;
+use crate::io::{Read, Seek, SeekFrom, Write};
 
 pub struct File {
     descr: syscall::FileDescriptor,
@@ -9,25 +9,68 @@ pub struct File {
 
 impl File {
     pub fn open(path: &str /*, flags: OpenFlags*/) -> io::Result<File> {
-        // TODO: Better errors
-        let descr = syscall::open(path).ok_or(io::Error {
-            kind: io::ErrorKind::NotFound,
-            error: "could not open file",
-        })?;
+        let descr = syscall::open(path)?;
+        Ok(File { descr })
+    }
+
+    /// Return the descriptor used by the kernel for this file.
+    pub fn descriptor(&self) -> syscall::FileDescriptor {
+        self.descr
+    }
 
+    /**
+     * Create a new handle to the same open file.
+     * Both handles share the current position in the file.
+     */
+    pub fn try_clone(&self) -> io::Result<File> {
+        let descr = syscall::dup(self.descr)?;
         Ok(File { descr })
     }
+
+    /**
+     * Make the descriptor `target` refer to this file, closing the file it
+     * previously referred to. This is mostly useful to redirect standard
+     * input and outputs before `exec` or `spawn`.
+     */
+    pub fn duplicate_to(&self, target: syscall::FileDescriptor) -> io::Result<()> {
+        syscall::dup2(self.descr, target)
+    }
+
+    /**
+     * Map a private copy of `len` bytes of the file from `offset` in memory,
+     * with the protection `prot` (see `syscall::PROT_*`). The offset must be
+     * a multiple of the page size. The mapping stays valid after the file is
+     * closed, it is released with `syscall::munmap`.
+     */
+    pub fn map(&self, offset: usize, len: usize, prot: u32) -> io::Result<*mut u8> {
+        syscall::mmap_file(self.descr, offset, len, prot)
+    }
+
+    /// Choose if the file should be closed when the process calls `exec`.
+    pub fn set_close_on_exec(&self, close_on_exec: bool) -> io::Result<()> {
+        syscall::set_close_on_exec(self.descr, close_on_exec)
+    }
+}
+
+/**
+ * Create an anonymous pipe and return its (read end, write end).
+ * Reads block while the pipe is empty and return 0 once every write end has
+ * been closed. Writes block while the pipe is full.
+ */
+pub fn pipe() -> (File, File) {
+    let (read_descr, write_descr) = syscall::pipe();
+    (File { descr: read_descr }, File { descr: write_descr })
 }
 
 impl Read for File {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        Ok(syscall::read(self.descr, buf))
+        syscall::read(self.descr, buf)
     }
 }
 
 impl Write for File {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        Ok(syscall::write(self.descr, buf))
+        syscall::write(self.descr, buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -38,12 +81,13 @@ impl Write for File {
 
 impl Seek for File {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        Ok(syscall::seek(self.descr, pos))
+        syscall::seek(self.descr, pos)
     }
 }
 
 impl Drop for File {
     fn drop(&mut self) {
-        syscall::close(self.descr)
+        // Errors cannot be reported from drop
+        let _ = syscall::close(self.descr);
     }
 }
@@ -0,0 +1,66 @@
+/*!
+ * Input and output traits, extended with handles to the standard streams.
+ *
+ * The kernel installs the standard input, output and error streams of each
+ * process on the descriptors 0, 1 and 2. They are inherited by children
+ * created with `fork` or `spawn` and can be redirected with
+ * `File::duplicate_to`.
+ */
+
+pub use rustberry_io::*;
+
+use crate::syscall;
+use crate::syscall::FileDescriptor;
+
+pub const STDIN_DESCRIPTOR: FileDescriptor = FileDescriptor::from_raw(0);
+pub const STDOUT_DESCRIPTOR: FileDescriptor = FileDescriptor::from_raw(1);
+pub const STDERR_DESCRIPTOR: FileDescriptor = FileDescriptor::from_raw(2);
+
+/// Handle to the standard input of the process.
+pub struct Stdin;
+
+/// Handle to the standard output of the process.
+pub struct Stdout;
+
+/// Handle to the standard error of the process.
+pub struct Stderr;
+
+pub fn stdin() -> Stdin {
+    Stdin
+}
+
+pub fn stdout() -> Stdout {
+    Stdout
+}
+
+pub fn stderr() -> Stderr {
+    Stderr
+}
+
+impl Read for Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        syscall::read(STDIN_DESCRIPTOR, buf)
+    }
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        syscall::write(STDOUT_DESCRIPTOR, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Standard streams are not buffered
+        Ok(())
+    }
+}
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        syscall::write(STDERR_DESCRIPTOR, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Standard streams are not buffered
+        Ok(())
+    }
+}
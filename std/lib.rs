@@ -1,32 +1,32 @@
 #![no_std]
-#![feature(asm, lang_items, panic_info_message)]
+#![feature(asm, lang_items, naked_functions, panic_info_message)]
 #![feature(alloc, allocator_api)]
 extern crate alloc;
 extern crate rlibc;
+#[macro_use]
+extern crate rustberry_abi as abi;
 
 extern crate rustberry_allocator as allocator;
-pub extern crate rustberry_io as io;
+extern crate rustberry_io;
 
 mod application_alloc;
+pub mod env;
 pub mod fs;
+pub mod io;
+pub mod signal;
 pub mod syscall;
 
 use application_alloc::GlobalAllocator;
 #[global_allocator]
 static ALLOCATOR: GlobalAllocator = GlobalAllocator;
 
-pub static mut STDIO: Option<fs::File> = None;
-
 #[macro_export]
 macro_rules! print
 {
     ($($arg:tt)*) =>
     {{
         use $crate::io::Write;
-        unsafe
-        {
-            let _ = write!($crate::STDIO.as_mut().unwrap(), $($arg)*);
-        }
+        let _ = write!($crate::io::stdout(), $($arg)*);
     }}
 }
 
@@ -36,10 +36,27 @@ macro_rules! println
     ($($arg:tt)*) =>
     {{
         use $crate::io::Write;
-        unsafe
-        {
-            let _ = writeln!($crate::STDIO.as_mut().unwrap(), $($arg)*);
-        }
+        let _ = writeln!($crate::io::stdout(), $($arg)*);
+    }}
+}
+
+#[macro_export]
+macro_rules! eprint
+{
+    ($($arg:tt)*) =>
+    {{
+        use $crate::io::Write;
+        let _ = write!($crate::io::stderr(), $($arg)*);
+    }}
+}
+
+#[macro_export]
+macro_rules! eprintln
+{
+    ($($arg:tt)*) =>
+    {{
+        use $crate::io::Write;
+        let _ = writeln!($crate::io::stderr(), $($arg)*);
     }}
 }
 
@@ -48,9 +65,14 @@ extern "C" {
 }
 
 #[no_mangle]
-pub extern "C" fn start() -> ! {
+pub extern "C" fn start(
+    argc: usize,
+    argv: *const env::RawStr,
+    envc: usize,
+    envp: *const env::RawVar,
+) -> ! {
     unsafe {
-        STDIO = Some(fs::File::open("dev/uart").unwrap());
+        env::init(argc, argv, envc, envp);
         main();
     }
     syscall::exit(0)
@@ -60,7 +82,7 @@ use core::panic::PanicInfo;
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     if let Some(loc) = info.location() {
-        print!(
+        eprint!(
             "\x1b[31;1mKernel panic !\x1b[0m\n\
              File {}, line {}, column {}:\n\
              \x1b[1m{}\x1b[0m\n",
@@ -70,7 +92,7 @@ fn panic(info: &PanicInfo) -> ! {
             info.message().unwrap()
         );
     } else {
-        print!(
+        eprint!(
             "\x1b[31;1mKernel panic !\x1b[0m\n\
              \x1b[1m{}\x1b[0m\n",
             info.message().unwrap()
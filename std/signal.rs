@@ -0,0 +1,72 @@
+/*!
+ * Handling of the signals sent by the kernel or other processes.
+ */
+
+use crate::io;
+use crate::syscall;
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGABRT: u32 = 6;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+
+pub enum SignalAction {
+    /// Terminate the process, except for SIGCHLD which is ignored
+    Default,
+    Ignore,
+    /// Call the function with the signal number. The signal is blocked while
+    /// the handler runs.
+    Handler(extern "C" fn(u32)),
+}
+
+/// Mask containing only the given signal.
+pub const fn mask(signal: u32) -> u32 {
+    1 << signal
+}
+
+// Signal handlers return here, with the stack pointer on the frame saved by
+// the kernel. This function must not touch the stack before sigreturn.
+#[naked]
+unsafe extern "C" fn restorer() -> ! {
+    asm!("svc $0" :: "i"(abi::number::sigreturn), "{r7}"(abi::number::sigreturn)
+                  :: "volatile");
+    loop {} // sigreturn never returns here
+}
+
+/**
+ * Choose what happens when the process receives the signal.
+ * SIGKILL cannot be caught or ignored. Signals in `blocked_in_handler` are
+ * additionally blocked while the handler runs.
+ */
+pub fn set_action(signal: u32, action: SignalAction, blocked_in_handler: u32) -> io::Result<()> {
+    let handler = match action {
+        SignalAction::Default => 0,
+        SignalAction::Ignore => 1,
+        SignalAction::Handler(handler) => handler as usize,
+    };
+    syscall::sigaction(signal, handler, restorer as usize, blocked_in_handler)
+}
+
+/// Add signals to the blocked mask and return the previous mask.
+pub fn block(signals: u32) -> io::Result<u32> {
+    syscall::sigprocmask(0, signals)
+}
+
+/// Remove signals from the blocked mask and return the previous mask.
+pub fn unblock(signals: u32) -> io::Result<u32> {
+    syscall::sigprocmask(1, signals)
+}
+
+/// Replace the blocked mask and return the previous one.
+pub fn set_blocked(signals: u32) -> io::Result<u32> {
+    syscall::sigprocmask(2, signals)
+}
@@ -1,33 +1,99 @@
-use io::SeekFrom;
+use crate::io;
+use crate::io::{Errno, SeekFrom};
+use alloc::vec::Vec;
 
-#[inline]
-pub fn reschedule() {
-    unsafe {
-        asm!("svc 0" :::: "volatile");
+pub use abi::{process_state, ProcessInfo, ResourceUsage, RUSAGE_CHILDREN, RUSAGE_SELF};
+pub use abi::{PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE};
+
+/**
+ * Raw system call stubs generated from `abi::syscall_table`.
+ * They take and return the content of the registers used by each system
+ * call without any conversion.
+ */
+pub mod raw {
+    macro_rules! reg_index {
+        (r0) => {
+            0
+        };
+        (r1) => {
+            1
+        };
+        (r2) => {
+            2
+        };
+        (r3) => {
+            3
+        };
+    }
+
+    // Type of the value of one register
+    macro_rules! reg_type {
+        ($reg:ident) => {
+            usize
+        };
+    }
+
+    macro_rules! syscall_stubs {
+        ($($num:tt => $name:ident($($reg:ident: $arg:ident),*) -> ($($ret:ident),*);)*) => {
+            $(
+                #[inline]
+                #[allow(unused_parens, unused_mut)]
+                pub unsafe fn $name($($arg: usize),*) -> ($(reg_type!($ret)),*) {
+                    let mut inputs = [0usize; 4];
+                    $(inputs[reg_index!($reg)] = $arg;)*
+                    let (o0, o1, o2): (usize, usize, usize);
+                    asm!("svc $7" : "={r0}"(o0), "={r1}"(o1), "={r2}"(o2)
+                                  : "{r0}"(inputs[0]), "{r1}"(inputs[1]),
+                                    "{r2}"(inputs[2]), "{r3}"(inputs[3]),
+                                    "i"(abi::number::$name),
+                                    "{r7}"(abi::number::$name)
+                                  : "memory" : "volatile");
+                    let outputs = [o0, o1, o2];
+                    ($(outputs[reg_index!($ret)]),*)
+                }
+            )*
+        };
     }
+
+    syscall_table!(syscall_stubs);
 }
 
-#[derive(Clone, Copy)]
-pub(crate) struct FileDescriptor(usize);
+// Decode the value returned in r0 by a system call, see `io::Errno`
+fn decode_result(value: usize) -> io::Result<usize> {
+    Errno::decode_return_value(value as u32)
+        .map(|value| value as usize)
+        .map_err(io::Error::from)
+}
 
 #[inline]
-pub(crate) fn read(file: FileDescriptor, buf: &mut [u8]) -> usize {
-    let read_bytes;
-    unsafe {
-        asm!("svc 1" : "={r0}"(read_bytes) : "{r0}"(file.0),
-                       "{r1}"(buf.as_ptr()), "{r2}"(buf.len()));
+pub fn reschedule() {
+    unsafe { raw::reschedule() }
+}
+
+/// Identifier of an open file inside the kernel.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FileDescriptor(pub(crate) usize);
+
+impl FileDescriptor {
+    pub const fn from_raw(descr: usize) -> FileDescriptor {
+        FileDescriptor(descr)
+    }
+
+    pub fn as_raw(self) -> usize {
+        self.0
     }
-    read_bytes
 }
 
 #[inline]
-pub(crate) fn write(file: FileDescriptor, buf: &[u8]) -> usize {
-    let written_bytes;
-    unsafe {
-        asm!("svc 2" : "={r0}"(written_bytes) : "{r0}"(file.0),
-                       "{r1}"(buf.as_ptr()), "{r2}"(buf.len()));
-    }
-    written_bytes
+pub(crate) fn read(file: FileDescriptor, buf: &mut [u8]) -> io::Result<usize> {
+    let read_bytes = unsafe { raw::read(file.0, buf.as_mut_ptr() as usize, buf.len()) };
+    decode_result(read_bytes)
+}
+
+#[inline]
+pub(crate) fn write(file: FileDescriptor, buf: &[u8]) -> io::Result<usize> {
+    let written_bytes = unsafe { raw::write(file.0, buf.as_ptr() as usize, buf.len()) };
+    decode_result(written_bytes)
 }
 
 /*pub enum OpenFlags {
@@ -39,107 +105,352 @@ pub(crate) fn write(file: FileDescriptor, buf: &[u8]) -> usize {
 }*/
 
 #[inline]
-pub(crate) fn open(path: &str /*, flags: OpenFlags*/) -> Option<FileDescriptor> {
-    let fdesc: i32;
-    unsafe {
-        asm!("svc 3" : "={r0}"(fdesc) : "{r0}"(path.as_ptr()),
-                       "{r1}"(path.len())/*, "{r2}"(flags as u32)*/ :: "volatile");
-    }
-    if fdesc >= 0 {
-        Some(FileDescriptor(fdesc as usize))
-    } else {
-        None
-    }
+pub(crate) fn open(path: &str /*, flags: OpenFlags*/) -> io::Result<FileDescriptor> {
+    let fdesc = unsafe { raw::open(path.as_ptr() as usize, path.len()) };
+    decode_result(fdesc).map(FileDescriptor)
 }
 
 #[inline]
-pub(crate) fn close(file: FileDescriptor) {
-    unsafe {
-        asm!("svc 4" :: "{r0}"(file.0) :: "volatile");
-    }
+pub(crate) fn close(file: FileDescriptor) -> io::Result<()> {
+    let result = unsafe { raw::close(file.0) };
+    decode_result(result).map(|_| ())
 }
 
 #[inline]
 pub fn exit(exit_code: u32) -> ! {
     unsafe {
-        asm!("svc 5" :: "{r0}"(exit_code) :: "volatile");
+        raw::exit(exit_code as usize);
     }
     loop {} // We should never come here !
 }
 
+/// Send a signal to a process.
 #[inline]
-pub fn kill(pid: usize) -> bool {
-    let result: u32;
-    unsafe {
-        asm!("svc 6" : "={r0}"(result) : "{r0}"(pid) :: "volatile");
-    }
-    result != 0
+pub fn kill(pid: usize, signal: u32) -> io::Result<()> {
+    let result = unsafe { raw::kill(pid, signal as usize) };
+    decode_result(result).map(|_| ())
+}
+
+#[inline]
+pub(crate) unsafe fn reserve_heap_pages(nb: isize) -> io::Result<usize> {
+    let first_allocated = raw::reserve_heap_pages(nb as usize);
+    decode_result(first_allocated)
+}
+
+/**
+ * Map `len` bytes of zeroed memory with the protection `prot` (a combination
+ * of `PROT_*`) and return its address. The memory is mapped at `addr` if it
+ * is not 0, otherwise the kernel chooses where.
+ */
+#[inline]
+pub fn mmap(addr: usize, len: usize, prot: u32) -> io::Result<*mut u8> {
+    let addr = unsafe { raw::mmap(addr, len, prot as usize) };
+    decode_result(addr).map(|addr| addr as *mut u8)
+}
+
+/**
+ * Map a private copy of `len` bytes of an open file from `offset`, which must
+ * be a multiple of the page size. Changes are not written back to the file.
+ */
+#[inline]
+pub(crate) fn mmap_file(
+    file: FileDescriptor,
+    offset: usize,
+    len: usize,
+    prot: u32,
+) -> io::Result<*mut u8> {
+    let addr = unsafe { raw::mmap_file(file.0, offset, len, prot as usize) };
+    decode_result(addr).map(|addr| addr as *mut u8)
+}
+
+/// Unmap the pages between `addr` and `addr + len`.
+#[inline]
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> io::Result<()> {
+    let result = raw::munmap(addr as usize, len);
+    decode_result(result).map(|_| ())
 }
 
+/// Change the protection of the pages between `addr` and `addr + len`.
 #[inline]
-pub(crate) unsafe fn reserve_heap_pages(nb: isize) -> usize {
-    let first_allocated;
-    asm!("svc 7" : "={r0}"(first_allocated) : "{r0}"(nb) :: "volatile");
-    first_allocated
+pub unsafe fn mprotect(addr: *mut u8, len: usize, prot: u32) -> io::Result<()> {
+    let result = raw::mprotect(addr as usize, len, prot as usize);
+    decode_result(result).map(|_| ())
 }
 
 #[inline]
 pub fn sleep(msec: usize) {
-    unsafe {
-        asm!("svc 8" :: "{r0}"(msec) :: "volatile");
+    unsafe { raw::sleep(msec) }
+}
+
+/// How a child process ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The child called exit with this code
+    Exited(u32),
+    /// The child was terminated by this signal, or by a fault raising it
+    Signaled(u32),
+}
+
+impl ExitStatus {
+    fn from_regs(kind: usize, value: usize) -> ExitStatus {
+        match kind {
+            0 => ExitStatus::Exited(value as u32),
+            _ => ExitStatus::Signaled(value as u32),
+        }
     }
 }
 
 pub struct ChildEvent {
     pub pid: usize,
-    pub exit_code: u32,
+    pub status: ExitStatus,
 }
 
+/// Wait until any child ends.
 #[inline]
 pub fn wait_children() -> ChildEvent {
-    let pid;
-    let exit_code;
-    unsafe {
-        asm!("svc 9" : "={r0}"(pid), "={r1}"(exit_code)
-             ::: "volatile");
+    let (pid, status_kind, status_value) = unsafe { raw::wait_children() };
+    ChildEvent {
+        pid,
+        status: ExitStatus::from_regs(status_kind, status_value),
+    }
+}
+
+/// Value of the pid given to `waitpid` to wait for any child
+pub const WAIT_ANY_CHILD: usize = !0;
+/// Flag of `waitpid` returning immediately if the child has not ended yet
+pub const WNOHANG: u32 = 1;
+
+/**
+ * Wait until the given child ends.
+ * Returns None if the WNOHANG flag is set and the child is still running.
+ */
+#[inline]
+pub fn waitpid(pid: usize, flags: u32) -> io::Result<Option<ChildEvent>> {
+    let (result, status_kind, status_value) = unsafe { raw::waitpid(pid, flags as usize) };
+    match decode_result(result)? {
+        0 => Ok(None),
+        pid => Ok(Some(ChildEvent {
+            pid,
+            status: ExitStatus::from_regs(status_kind, status_value),
+        })),
     }
-    ChildEvent { pid, exit_code }
 }
 
+/**
+ * Add `increment` to the nice value of the current process and return the
+ * new value. Nice values go from -20 to 19, higher values get less CPU time.
+ * Only init can use a negative increment.
+ */
 #[inline]
-pub(crate) fn seek(fdesc: FileDescriptor, seek_pos: SeekFrom) -> u64 {
+pub fn nice(increment: i32) -> io::Result<i32> {
+    let result = unsafe { raw::nice(increment as usize) };
+    decode_result(result).map(|nice| nice as i32 - abi::NICE_OFFSET)
+}
+
+/// Get the nice value of a child process, or of the current process if `pid` is 0.
+#[inline]
+pub fn getpriority(pid: usize) -> io::Result<i32> {
+    let result = unsafe { raw::getpriority(pid) };
+    decode_result(result).map(|nice| nice as i32 - abi::NICE_OFFSET)
+}
+
+/**
+ * Set the nice value of a child process, or of the current process if `pid`
+ * is 0. The value is clamped between -20 and 19. Only init can lower the nice
+ * value of a process.
+ */
+#[inline]
+pub fn setpriority(pid: usize, nice: i32) -> io::Result<()> {
+    let result = unsafe { raw::setpriority(pid, nice as usize) };
+    decode_result(result).map(|_| ())
+}
+
+/**
+ * Get the maximum number of pages that a child process, or the current
+ * process if `pid` is 0, can map. None means there is no limit.
+ */
+#[inline]
+pub fn get_page_limit(pid: usize) -> io::Result<Option<usize>> {
+    let result = unsafe { raw::get_page_limit(pid) };
+    decode_result(result).map(|limit| match limit as u32 {
+        abi::NO_PAGE_LIMIT => None,
+        limit => Some(limit as usize),
+    })
+}
+
+/**
+ * Limit the number of pages that a child process, or the current process if
+ * `pid` is 0, can map. The limit cannot be higher than the one of the
+ * current process. Children inherit the limit of their parent. A limit of
+ * 0 pages is raised to 1, as 0 means no limit for the kernel.
+ */
+#[inline]
+pub fn set_page_limit(pid: usize, nb_pages: Option<usize>) -> io::Result<()> {
+    let nb_pages = nb_pages.map_or(abi::NO_PAGE_LIMIT as usize, |nb_pages| nb_pages.max(1));
+    let result = unsafe { raw::set_page_limit(pid, nb_pages) };
+    decode_result(result).map(|_| ())
+}
+
+/**
+ * Get the processor time used by the current process (RUSAGE_SELF) or by
+ * its children that have ended (RUSAGE_CHILDREN).
+ */
+#[inline]
+pub fn getrusage(who: u32) -> io::Result<ResourceUsage> {
+    let mut usage = ResourceUsage::default();
+    let result = unsafe { raw::getrusage(who as usize, &mut usage as *mut _ as usize) };
+    decode_result(result).map(|_| usage)
+}
+
+/// Describe all the processes of the system.
+pub fn list_processes() -> io::Result<Vec<ProcessInfo>> {
+    let mut infos: Vec<ProcessInfo> = Vec::new();
+    loop {
+        let capacity = infos.capacity();
+        let nb_processes =
+            decode_result(unsafe { raw::list_processes(infos.as_mut_ptr() as usize, capacity) })?;
+        if nb_processes <= capacity {
+            unsafe { infos.set_len(nb_processes) };
+            return Ok(infos);
+        }
+        // Processes may be created meanwhile, leave some room for them
+        infos.reserve_exact(nb_processes + 4);
+    }
+}
+
+#[inline]
+pub(crate) fn seek(fdesc: FileDescriptor, seek_pos: SeekFrom) -> io::Result<u64> {
     let seek_origin;
     let seek_low;
     let seek_high;
     match seek_pos {
         SeekFrom::Start(off) => {
             seek_origin = 0;
-            seek_low = off as u32;
-            seek_high = (off >> 32) as u32;
+            seek_low = off as u32 as usize;
+            seek_high = (off >> 32) as usize;
         }
         SeekFrom::End(off) => {
             seek_origin = 1;
-            seek_low = off as u32;
-            seek_high = (off as u64 >> 32) as u32;
+            seek_low = off as u32 as usize;
+            seek_high = (off as u64 >> 32) as usize;
         }
         SeekFrom::Current(off) => {
             seek_origin = 2;
-            seek_low = off as u32;
-            seek_high = (off as u64 >> 32) as u32;
+            seek_low = off as u32 as usize;
+            seek_high = (off as u64 >> 32) as usize;
         }
     }
 
-    let offset_high: u32;
-    let offset_low: u32;
-    unsafe {
-        asm!("svc 10" : "={r0}"(offset_high), "={r1}"(offset_low) :
-                        "{r0}"(fdesc.0), "{r1}"(seek_origin),
-                        "{r2}"(seek_high), "{r3}"(seek_low) :: "volatile");
+    let (result, offset_high, offset_low) =
+        unsafe { raw::seek(fdesc.0, seek_origin, seek_high, seek_low) };
+    decode_result(result)?;
+    Ok((offset_high as u64) << 32 | offset_low as u64)
+}
+
+// Arguments are passed to the kernel as (address, length) pairs
+fn raw_str_array(strings: &[&str]) -> Vec<[usize; 2]> {
+    strings
+        .iter()
+        .map(|string| [string.as_ptr() as usize, string.len()])
+        .collect()
+}
+
+/**
+ * Launch the executable at `path` in a new child process and return its pid.
+ * The child receives `args` as argument vector and inherits the environment
+ * of the current process.
+ */
+#[inline]
+pub fn spawn(path: &str, args: &[&str]) -> io::Result<usize> {
+    let raw_args = raw_str_array(args);
+    let pid = unsafe {
+        raw::spawn(
+            path.as_ptr() as usize,
+            path.len(),
+            raw_args.as_ptr() as usize,
+            raw_args.len(),
+        )
+    };
+    decode_result(pid)
+}
+
+/**
+ * Duplicate the current process.
+ * Returns the pid of the child in the parent process and 0 in the child.
+ * Fails if there is not enough memory for the child.
+ */
+#[inline]
+pub fn fork() -> io::Result<usize> {
+    let pid = unsafe { raw::fork() };
+    decode_result(pid)
+}
+
+/**
+ * Replace the program of the current process by the executable at `path`
+ * with `args` as argument vector.
+ * This function only returns if the executable could not be loaded.
+ */
+#[inline]
+pub fn exec(path: &str, args: &[&str]) -> io::Error {
+    let raw_args = raw_str_array(args);
+    let error = unsafe {
+        raw::exec(
+            path.as_ptr() as usize,
+            path.len(),
+            raw_args.as_ptr() as usize,
+            raw_args.len(),
+        )
+    };
+    match decode_result(error) {
+        Err(err) => err,
+        Ok(_) => io::Error::from(Errno::Other),
     }
-    (offset_high as u64) << 32 | offset_low as u64
 }
 
-/*#[inline]
-pub fn spawn() {
-    unimplemented!() // svc 11
-}*/
+#[inline]
+pub(crate) fn set_close_on_exec(file: FileDescriptor, close_on_exec: bool) -> io::Result<()> {
+    let result = unsafe { raw::set_close_on_exec(file.0, close_on_exec as usize) };
+    decode_result(result).map(|_| ())
+}
+
+/// Create a pipe and return its read and write file descriptors.
+#[inline]
+pub(crate) fn pipe() -> (FileDescriptor, FileDescriptor) {
+    let (read_descr, write_descr) = unsafe { raw::pipe() };
+    (FileDescriptor(read_descr), FileDescriptor(write_descr))
+}
+
+/// Create a new file descriptor sharing the same open file and offset.
+#[inline]
+pub(crate) fn dup(file: FileDescriptor) -> io::Result<FileDescriptor> {
+    let new_descr = unsafe { raw::dup(file.0) };
+    decode_result(new_descr).map(FileDescriptor)
+}
+
+/**
+ * Make `target` refer to the same open file as `file`.
+ * If `target` was already open, it is closed first.
+ */
+#[inline]
+pub fn dup2(file: FileDescriptor, target: FileDescriptor) -> io::Result<()> {
+    let result = unsafe { raw::dup2(file.0, target.0) };
+    decode_result(result).map(|_| ())
+}
+
+/**
+ * Set the action of a signal. `handler` is 0 for the default action, 1 to
+ * ignore the signal, or the address of the handler. When the handler returns,
+ * it jumps to `restorer` that must call `sigreturn`.
+ */
+#[inline]
+pub(crate) fn sigaction(signal: u32, handler: usize, restorer: usize, mask: u32) -> io::Result<()> {
+    let result = unsafe { raw::sigaction(signal as usize, handler, restorer, mask as usize) };
+    decode_result(result).map(|_| ())
+}
+
+/// Change the blocked signals mask and return the previous one.
+#[inline]
+pub(crate) fn sigprocmask(how: u32, mask: u32) -> io::Result<u32> {
+    let (result, old_mask) = unsafe { raw::sigprocmask(how as usize, mask as usize) };
+    decode_result(result).map(|_| old_mask as u32)
+}